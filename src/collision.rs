@@ -0,0 +1,323 @@
+use crate::grid::{Grid, Segment};
+use vecmath::Vector2;
+
+/// How many times overlaps are pushed out per step. Pushing out of one tile
+/// can push into another in corners, so a few passes are needed to settle.
+const RESOLVE_PASSES: usize = 3;
+
+/// How high a step can be walked up without jumping, in wall heights.
+pub const MAX_STEP: f32 = 0.3;
+
+/// Move a circle of `radius` centred on `position` by `motion`, in grid units,
+/// returning where it ends up.
+///
+/// The circle is pushed out of any solid tile it overlaps along the shortest
+/// way out, so motion into a wall is cancelled while motion along it carries
+/// on, sliding the circle along the wall. Anything outside the grid is solid.
+pub fn move_circle(
+    grid: &Grid,
+    position: Vector2<f32>,
+    motion: Vector2<f32>,
+    radius: f32,
+) -> Vector2<f32> {
+    move_blocked_by(position, motion, radius, grid, |x, y| grid.is_solid(x, y))
+}
+
+/// Move a body of `radius` with its feet at height `feet` and `height` tall
+/// like [`move_circle`]. As well as solid tiles it can't go onto floors more
+/// than [`MAX_STEP`] above its feet, or under ceilings too low for it.
+pub fn move_body(
+    grid: &Grid,
+    position: Vector2<f32>,
+    motion: Vector2<f32>,
+    radius: f32,
+    feet: f32,
+    height: f32,
+) -> Vector2<f32> {
+    move_blocked_by(position, motion, radius, grid, |x, y| {
+        let floor = grid.floor_height_at(x, y);
+        grid.is_solid(x, y)
+            || floor > feet + MAX_STEP
+            || grid.ceiling_height_at(x, y) - floor.max(feet) < height
+    })
+}
+
+/// The highest floor and lowest ceiling over the open tiles a circle of
+/// `radius` centred on `position` overlaps, which are what a body there
+/// stands on and has to fit under.
+pub fn floor_and_ceiling(grid: &Grid, position: Vector2<f32>, radius: f32) -> (f32, f32) {
+    let [x, y] = [position[0].floor() as i32, position[1].floor() as i32];
+    let centre = (grid.floor_height_at(x, y), grid.ceiling_height_at(x, y));
+    nearby_tiles(position, radius)
+        .filter(|&(x, y)| !grid.is_solid(x, y) && penetration(position, radius, x, y).is_some())
+        .fold(centre, |(floor, ceiling), (x, y)| {
+            (
+                floor.max(grid.floor_height_at(x, y)),
+                ceiling.min(grid.ceiling_height_at(x, y)),
+            )
+        })
+}
+
+/// Move a circle, treating the tiles `is_solid` says are solid as walls.
+fn move_blocked_by(
+    position: Vector2<f32>,
+    motion: Vector2<f32>,
+    radius: f32,
+    grid: &Grid,
+    is_solid: impl Fn(i32, i32) -> bool,
+) -> Vector2<f32> {
+    // Split large moves into steps no longer than half the radius so fast
+    // movement can't tunnel through thin corners.
+    let length = (motion[0] * motion[0] + motion[1] * motion[1]).sqrt();
+    let steps = (length / (radius * 0.5)).ceil().max(1.0) as usize;
+    let step = [motion[0] / steps as f32, motion[1] / steps as f32];
+
+    let mut position = position;
+    for _ in 0..steps {
+        position[0] += step[0];
+        position[1] += step[1];
+        for _ in 0..RESOLVE_PASSES {
+            if !resolve(grid, &mut position, radius, &is_solid) {
+                break;
+            }
+        }
+    }
+    position
+}
+
+/// Push the circle out of every solid tile and thin wall it overlaps, other
+/// than portals it's walking into the face of. Returns whether it had to be
+/// moved.
+fn resolve(
+    grid: &Grid,
+    position: &mut Vector2<f32>,
+    radius: f32,
+    is_solid: &impl Fn(i32, i32) -> bool,
+) -> bool {
+    let mut moved = false;
+    for (x, y) in nearby_tiles(*position, radius) {
+        let push = if let Some(segment) = grid.segment_at(x, y) {
+            segment_penetration(*position, radius, x, y, segment)
+        } else if grid
+            .portal_at(x, y)
+            .is_some_and(|portal| portal.lets_in(*position))
+        {
+            continue;
+        } else if is_solid(x, y) {
+            penetration(*position, radius, x, y)
+        } else {
+            continue;
+        };
+        if let Some(push) = push {
+            position[0] += push[0];
+            position[1] += push[1];
+            moved = true;
+        }
+    }
+    moved
+}
+
+/// The tiles a circle's bounding box touches.
+fn nearby_tiles(position: Vector2<f32>, radius: f32) -> impl Iterator<Item = (i32, i32)> {
+    let min_x = (position[0] - radius).floor() as i32;
+    let max_x = (position[0] + radius).floor() as i32;
+    let min_y = (position[1] - radius).floor() as i32;
+    let max_y = (position[1] + radius).floor() as i32;
+    (min_y..=max_y).flat_map(move |y| (min_x..=max_x).map(move |x| (x, y)))
+}
+
+/// How far to move a circle to get it out of the tile at `x`, `y`, or `None`
+/// if they don't overlap.
+fn penetration(position: Vector2<f32>, radius: f32, x: i32, y: i32) -> Option<Vector2<f32>> {
+    let closest = [
+        position[0].clamp(x as f32, x as f32 + 1.0),
+        position[1].clamp(y as f32, y as f32 + 1.0),
+    ];
+    let offset = [position[0] - closest[0], position[1] - closest[1]];
+    let distance = (offset[0] * offset[0] + offset[1] * offset[1]).sqrt();
+
+    if distance >= radius {
+        return None;
+    }
+    if distance > f32::EPSILON {
+        let depth = radius - distance;
+        return Some([offset[0] / distance * depth, offset[1] / distance * depth]);
+    }
+
+    // The centre is inside the tile, so leave through the nearest edge.
+    let exits = [
+        (x as f32 - radius - position[0], 0.0),
+        (x as f32 + 1.0 + radius - position[0], 0.0),
+        (0.0, y as f32 - radius - position[1]),
+        (0.0, y as f32 + 1.0 + radius - position[1]),
+    ];
+    exits
+        .iter()
+        .min_by(|a, b| (a.0 + a.1).abs().total_cmp(&(b.0 + b.1).abs()))
+        .map(|&(dx, dy)| [dx, dy])
+}
+
+/// How far to move a circle to get it off the thin wall in the tile at `x`,
+/// `y`, or `None` if they don't overlap.
+fn segment_penetration(
+    position: Vector2<f32>,
+    radius: f32,
+    x: i32,
+    y: i32,
+    segment: &Segment,
+) -> Option<Vector2<f32>> {
+    let start = [x as f32 + segment.start[0], y as f32 + segment.start[1]];
+    let along_wall = [
+        segment.end[0] - segment.start[0],
+        segment.end[1] - segment.start[1],
+    ];
+    let length_squared = along_wall[0] * along_wall[0] + along_wall[1] * along_wall[1];
+    let t = if length_squared > f32::EPSILON {
+        (((position[0] - start[0]) * along_wall[0] + (position[1] - start[1]) * along_wall[1])
+            / length_squared)
+            .clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let closest = [start[0] + along_wall[0] * t, start[1] + along_wall[1] * t];
+    let offset = [position[0] - closest[0], position[1] - closest[1]];
+    let distance = (offset[0] * offset[0] + offset[1] * offset[1]).sqrt();
+
+    if distance >= radius {
+        return None;
+    }
+    if distance > f32::EPSILON {
+        let depth = radius - distance;
+        return Some([offset[0] / distance * depth, offset[1] / distance * depth]);
+    }
+
+    // The centre is right on the wall, so step off it to one side.
+    let length = length_squared.sqrt().max(f32::EPSILON);
+    Some([
+        -along_wall[1] / length * radius,
+        along_wall[0] / length * radius,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::TileKind;
+    use crate::portal::Portal;
+
+    /// A grid from rows of `#` for walls and anything else for empty space.
+    fn grid(rows: &[&str]) -> Grid {
+        let mut grid = Grid::new(rows[0].len() as i32, rows.len() as i32);
+        for (y, row) in rows.iter().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                grid.tiles[x + y * rows[0].len()] = (c == '#') as u8;
+            }
+        }
+        grid
+    }
+
+    fn assert_near(actual: Vector2<f32>, expected: Vector2<f32>) {
+        assert!(
+            (actual[0] - expected[0]).abs() < 1e-4 && (actual[1] - expected[1]).abs() < 1e-4,
+            "{:?} isn't {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn slides_along_a_wall() {
+        let grid = grid(&["#####", "#...#", "#...#", "#####"]);
+        let position = move_circle(&grid, [1.5, 1.5], [0.5, -0.5], 0.25);
+        assert_near(position, [2.0, 1.25]);
+    }
+
+    #[test]
+    fn stops_in_a_corner() {
+        let grid = grid(&["#####", "#...#", "#...#", "#####"]);
+        let position = move_circle(&grid, [1.5, 1.5], [-1.0, -1.0], 0.25);
+        assert_near(position, [1.25, 1.25]);
+    }
+
+    #[test]
+    fn large_moves_stop_at_the_first_wall() {
+        let grid = grid(&["#######", "#..#..#", "#######"]);
+        let position = move_circle(&grid, [1.5, 1.5], [3.0, 0.0], 0.25);
+        assert_near(position, [2.75, 1.5]);
+    }
+
+    #[test]
+    fn slides_along_a_thin_wall() {
+        // corner to corner, from top left to bottom right of the tile at 2, 2
+        let mut grid = grid(&[".....", ".....", ".....", ".....", "....."]);
+        grid.kinds[2] = TileKind::Segment;
+        grid.segments[2] = Some(Segment {
+            start: [0.0, 0.0],
+            end: [1.0, 1.0],
+        });
+        grid.tiles[2 + 2 * 5] = 2;
+        let end = move_circle(&grid, [2.2, 2.8], [0.5, 0.0], 0.25);
+        // kept a radius off the wall, having slid along it by as much of the
+        // move as ran along it
+        let off_wall = 0.25 * std::f32::consts::SQRT_2;
+        assert_near(end, [2.75 - off_wall / 2.0, 2.75 + off_wall / 2.0]);
+    }
+
+    #[test]
+    fn portals_can_only_be_walked_into_from_the_front() {
+        let mut grid = grid(&["#######", "#.....#", "#.....#", "#######"]);
+        grid.kinds[2] = TileKind::Portal;
+        grid.tiles[3 + 7] = 2;
+        grid.portals.insert(
+            3 + 7,
+            Portal {
+                tile: [3, 1],
+                facing: [1, 0],
+                link: [3, 1],
+            },
+        );
+        let from_front = move_circle(&grid, [4.5, 1.5], [-1.0, 0.0], 0.25);
+        assert_near(from_front, [3.5, 1.5]);
+        let from_behind = move_circle(&grid, [1.5, 1.5], [1.5, 0.0], 0.25);
+        assert_near(from_behind, [2.75, 1.5]);
+        let from_side = move_circle(&grid, [3.5, 2.5], [0.0, -1.0], 0.25);
+        assert_near(from_side, [3.5, 2.25]);
+    }
+
+    #[test]
+    fn climbs_steps_up_to_max_step() {
+        let mut grid = grid(&["######", "#....#", "######"]);
+        grid.floor_heights[3 + 6] = MAX_STEP;
+        let position = move_body(&grid, [1.5, 1.5], [2.0, 0.0], 0.25, 0.0, 0.5);
+        assert_near(position, [3.5, 1.5]);
+    }
+
+    #[test]
+    fn stops_at_steps_above_max_step() {
+        let mut grid = grid(&["######", "#....#", "######"]);
+        grid.floor_heights[3 + 6] = MAX_STEP + 0.1;
+        let position = move_body(&grid, [1.5, 1.5], [2.0, 0.0], 0.25, 0.0, 0.5);
+        assert_near(position, [2.75, 1.5]);
+
+        // but can get onto it from higher up, like at the top of a jump
+        let position = move_body(&grid, [1.5, 1.5], [2.0, 0.0], 0.25, 0.2, 0.5);
+        assert_near(position, [3.5, 1.5]);
+    }
+
+    #[test]
+    fn stops_under_low_ceilings() {
+        let mut grid = grid(&["######", "#....#", "######"]);
+        grid.ceiling_heights[3 + 6] = 0.4;
+        let position = move_body(&grid, [1.5, 1.5], [2.0, 0.0], 0.25, 0.0, 0.5);
+        assert_near(position, [2.75, 1.5]);
+    }
+
+    #[test]
+    fn stands_on_the_highest_floor_underneath() {
+        let mut grid = grid(&["######", "#....#", "######"]);
+        grid.floor_heights[3 + 6] = 0.2;
+        grid.ceiling_heights[3 + 6] = 0.9;
+        assert_eq!(floor_and_ceiling(&grid, [2.5, 1.5], 0.25), (0.0, 1.0));
+        assert_eq!(floor_and_ceiling(&grid, [2.9, 1.5], 0.25), (0.2, 0.9));
+    }
+}
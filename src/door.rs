@@ -0,0 +1,47 @@
+/// Tile value of a sliding door.
+pub const DOOR: u8 = 5;
+
+/// How long a door takes to slide fully open or closed, in seconds.
+const SLIDE_TIME: f32 = 1.0;
+
+/// A door drawn as a thin wall across the middle of its tile, which slides
+/// sideways into the neighbouring wall when opened.
+#[derive(Copy, Clone)]
+pub struct Door {
+    /// How far the door has slid open, from 0 (closed) to 1 (open).
+    pub open: f32,
+    /// Whether the door is opening or closing.
+    pub opening: bool,
+    /// Whether the door runs along the x axis, through the middle of the
+    /// tile's y range, rather than along the y axis.
+    pub horizontal: bool,
+}
+
+impl Door {
+    pub fn new(horizontal: bool) -> Door {
+        Door {
+            open: 0.0,
+            opening: false,
+            horizontal,
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.opening = !self.opening;
+    }
+
+    /// Slide the door towards open or closed.
+    pub fn update(&mut self, delta: f32) {
+        let step = delta / SLIDE_TIME;
+        self.open = if self.opening {
+            (self.open + step).min(1.0)
+        } else {
+            (self.open - step).max(0.0)
+        };
+    }
+
+    /// Whether the door is open far enough to walk through.
+    pub fn is_passable(&self) -> bool {
+        self.open >= 0.9
+    }
+}
@@ -1,4 +1,3 @@
-use crate::{HEIGHT, WIDTH};
 use line_drawing::Bresenham;
 
 pub struct Point {
@@ -6,35 +5,70 @@ pub struct Point {
     pub y: i32,
 }
 
-pub fn pixel(screen: &mut [u8], x: i32, y: i32, colour: [u8; 4]) {
-    let x = x.clamp(0, WIDTH - 1);
-    let y = y.clamp(0, HEIGHT - 1);
-    let i = (x * 4 + y * WIDTH * 4) as usize;
-    screen[i..i + 4].copy_from_slice(&colour);
+/// A mutable view of an RGBA8 frame buffer, laid out row by row, along with
+/// its dimensions in pixels.
+pub struct Frame<'a> {
+    pub pixels: &'a mut [u8],
+    pub width: i32,
+    pub height: i32,
 }
 
-pub fn line(screen: &mut [u8], p1: &Point, p2: &Point, colour: [u8; 4]) {
-    let p1 = (p1.x.clamp(0, WIDTH - 1), p1.y.clamp(0, HEIGHT - 1));
-    let p2 = (p2.x.clamp(0, WIDTH - 1), p2.y.clamp(0, HEIGHT - 1));
+impl<'a> Frame<'a> {
+    pub fn new(pixels: &'a mut [u8], width: i32, height: i32) -> Frame<'a> {
+        assert_eq!(pixels.len(), (width * height * 4) as usize);
+        Frame {
+            pixels,
+            width,
+            height,
+        }
+    }
+}
+
+/// Blend `over` on top of `under` by `over`'s alpha. The result keeps
+/// `under`'s alpha.
+pub fn blend(under: [u8; 4], over: [u8; 4]) -> [u8; 4] {
+    match over[3] {
+        0 => under,
+        255 => [over[0], over[1], over[2], under[3]],
+        alpha => {
+            let alpha = alpha as f32 / 255.0;
+            let mut c = under;
+            for (c, over) in c[0..3].iter_mut().zip(over) {
+                *c = (*c as f32 + (over as f32 - *c as f32) * alpha) as u8;
+            }
+            c
+        }
+    }
+}
+
+pub fn line(frame: &mut Frame, p1: &Point, p2: &Point, colour: [u8; 4]) {
+    let p1 = (
+        p1.x.clamp(0, frame.width - 1),
+        p1.y.clamp(0, frame.height - 1),
+    );
+    let p2 = (
+        p2.x.clamp(0, frame.width - 1),
+        p2.y.clamp(0, frame.height - 1),
+    );
 
     for (x, y) in Bresenham::new(p1, p2) {
-        let i = (x * 4 + y * WIDTH * 4) as usize;
+        let i = (x * 4 + y * frame.width * 4) as usize;
 
-        screen[i..i + 4].copy_from_slice(&colour);
+        frame.pixels[i..i + 4].copy_from_slice(&colour);
     }
 }
 
-pub fn rect_filled(screen: &mut [u8], lower: &Point, upper: &Point, colour: [u8; 4]) {
-    for y in lower.y..upper.y {
-        for x in lower.x..upper.x {
-            let i = (x * 4 + y * WIDTH * 4) as usize;
-            screen[i..i + 4].copy_from_slice(&colour);
+pub fn rect_filled(frame: &mut Frame, lower: &Point, upper: &Point, colour: [u8; 4]) {
+    for y in lower.y.max(0)..upper.y.min(frame.height) {
+        for x in lower.x.max(0)..upper.x.min(frame.width) {
+            let i = (x * 4 + y * frame.width * 4) as usize;
+            frame.pixels[i..i + 4].copy_from_slice(&colour);
         }
     }
 }
 
-pub fn clear(screen: &mut [u8]) {
-    for (i, byte) in screen.iter_mut().enumerate() {
+pub fn clear(frame: &mut Frame) {
+    for (i, byte) in frame.pixels.iter_mut().enumerate() {
         *byte = if i % 4 == 3 { 255 } else { 0 };
     }
 }
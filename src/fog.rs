@@ -0,0 +1,68 @@
+use serde::Deserialize;
+
+/// How fog thickens with distance.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub enum FogMode {
+    /// No fog at all.
+    None,
+    /// Clear up to `start` tiles away, fading to solid fog at `end`.
+    Linear { start: f32, end: f32 },
+    /// Fades as `1 - e^(-density * distance)`, never quite reaching solid fog.
+    Exponential { density: f32 },
+}
+
+/// Distance fog, blending far away surfaces into a colour.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct Fog {
+    pub mode: FogMode,
+    #[serde(default)]
+    pub colour: [u8; 3],
+}
+
+impl Fog {
+    /// No fog.
+    pub fn none() -> Fog {
+        Fog {
+            mode: FogMode::None,
+            colour: [0, 0, 0],
+        }
+    }
+
+    /// How much of a colour `distance` tiles away is replaced by fog, from 0
+    /// to 1.
+    pub fn amount(&self, distance: f32) -> f32 {
+        match self.mode {
+            FogMode::None => 0.0,
+            FogMode::Linear { start, end } => {
+                if distance <= start {
+                    0.0
+                } else if distance >= end {
+                    1.0
+                } else {
+                    (distance - start) / (end - start)
+                }
+            }
+            FogMode::Exponential { density } => 1.0 - (-density * distance).exp(),
+        }
+    }
+
+    /// Blend `colour` seen from `distance` tiles away into the fog, keeping
+    /// its alpha.
+    pub fn apply(&self, colour: [u8; 4], distance: f32) -> [u8; 4] {
+        let amount = self.amount(distance);
+        if amount <= 0.0 {
+            return colour;
+        }
+        let mut c = colour;
+        for (c, fog) in c[0..3].iter_mut().zip(self.colour) {
+            *c = (*c as f32 + (fog as f32 - *c as f32) * amount) as u8;
+        }
+        c
+    }
+}
+
+impl Default for Fog {
+    fn default() -> Self {
+        Self::none()
+    }
+}
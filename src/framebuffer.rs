@@ -0,0 +1,62 @@
+use image::{ColorType, ImageResult};
+use std::path::Path;
+
+/// An offscreen RGBA frame with the same layout as the `pixels` frame, so the
+/// `World` can be drawn without a window or GPU.
+pub struct Framebuffer {
+    pixels: Vec<u8>,
+    width: i32,
+    height: i32,
+}
+
+impl Framebuffer {
+    pub fn new(width: i32, height: i32) -> Framebuffer {
+        Framebuffer {
+            pixels: vec![0; buffer_len(width, height)],
+            width,
+            height,
+        }
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn resize(&mut self, width: i32, height: i32) {
+        self.pixels.resize(buffer_len(width, height), 0);
+        self.width = width;
+        self.height = height;
+    }
+
+    pub fn frame(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn frame_mut(&mut self) -> &mut [u8] {
+        &mut self.pixels
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> ImageResult<()> {
+        image::save_buffer(
+            path,
+            &self.pixels,
+            self.width as u32,
+            self.height as u32,
+            ColorType::Rgba8,
+        )
+    }
+}
+
+/// The number of bytes in a `width` by `height` RGBA frame.
+fn buffer_len(width: i32, height: i32) -> usize {
+    let width = usize::try_from(width).expect("negative frame width");
+    let height = usize::try_from(height).expect("negative frame height");
+    width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(4))
+        .expect("frame too large")
+}
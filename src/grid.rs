@@ -1,35 +1,368 @@
-use crate::{line, rect_filled, Point, HEIGHT};
-use image::GenericImageView;
+use crate::door::{Door, DOOR};
+use crate::drawing::Frame;
+use crate::light::Light;
+use crate::portal::Portal;
+use crate::{line, rect_filled, Point};
+use image::{GenericImageView, ImageResult};
+use std::collections::HashMap;
+use std::path::Path;
 
+/// Floor value of a hole in the floor, looking down onto the storey below.
+pub const OPENING: u8 = 255;
+
+/// How tiles with a given value behave.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TileKind {
+    Empty,
+    Wall,
+    Door,
+    /// A solid wall with a texture that can be seen through where its alpha
+    /// is below 255, like windows and grates.
+    Transparent,
+    /// A thin wall running between two points inside the tile, given by the
+    /// tile value's [`Segment`].
+    Segment,
+    /// A solid wall that reflects rays, drawn with its texture blended over
+    /// the reflection as a tint.
+    Mirror,
+    /// One end of a pair of portals, given by the tile's [`Portal`]. Drawn
+    /// with its texture blended over whatever is seen through it.
+    Portal,
+    /// Empty space that takes the player up to the storey above when they
+    /// step into it.
+    StairsUp,
+    /// Empty space that takes the player down to the storey below when they
+    /// step into it.
+    StairsDown,
+}
+
+impl TileKind {
+    /// Whether tiles of this kind have nothing in them to see or bump into.
+    pub fn is_open(self) -> bool {
+        matches!(
+            self,
+            TileKind::Empty | TileKind::StairsUp | TileKind::StairsDown
+        )
+    }
+
+    /// Whether tiles of this kind are whole walls, which doors can be hung
+    /// between.
+    pub fn is_wall(self) -> bool {
+        matches!(
+            self,
+            TileKind::Wall | TileKind::Transparent | TileKind::Mirror | TileKind::Portal
+        )
+    }
+}
+
+/// A thin wall inside a tile, from `start` to `end` in tile coordinates,
+/// where (0, 0) is the tile's top left corner and (1, 1) its bottom right.
+#[derive(Copy, Clone, Debug)]
+pub struct Segment {
+    pub start: [f32; 2],
+    pub end: [f32; 2],
+}
+
+/// One storey of a level.
+#[derive(Clone)]
 pub struct Grid {
     pub tiles: Vec<u8>,
+    /// Kind of each tile value, indexed by the value.
+    pub kinds: Vec<TileKind>,
+    /// Height of each tile value's walls, in wall heights, indexed by the
+    /// value.
+    pub heights: Vec<f32>,
+    /// The thin wall of each [`TileKind::Segment`] tile value, indexed by the
+    /// value.
+    pub segments: Vec<Option<Segment>>,
+    /// Floor texture of each tile, zero for an untextured floor and
+    /// [`OPENING`] for a hole.
+    pub floor: Vec<u8>,
+    /// Ceiling texture of each tile, zero for open sky.
+    pub ceiling: Vec<u8>,
+    /// Height of the floor of each tile, in wall heights. Walls stand on
+    /// their tile's floor.
+    pub floor_heights: Vec<f32>,
+    /// Height of the ceiling of each tile, in wall heights.
+    pub ceiling_heights: Vec<f32>,
+    /// Base light level of each tile, from 0 for pitch black to 255 for
+    /// fully lit.
+    pub light: Vec<u8>,
+    /// Point lights shining on the grid.
+    pub lights: Vec<Light>,
+    /// Brightness of each tile from 0 to 1, combining the base light levels
+    /// and the point lights. Updated by [`Grid::bake_lights`].
+    pub light_map: Vec<f32>,
+    /// State of each door, keyed by tile index.
+    pub doors: HashMap<usize, Door>,
+    /// Where each portal leads, keyed by tile index.
+    pub portals: HashMap<usize, Portal>,
     pub width: i32,
     pub height: i32,
     pub tile_size: i32,
 }
 
 impl Grid {
-    pub fn init(&mut self) {
-        let grid_image = image::open("assets/grid.png").unwrap();
-        self.width = grid_image.width() as i32;
-        self.height = grid_image.height() as i32;
-        self.tiles
-            .resize((grid_image.width() * grid_image.height()) as usize, 0);
-        self.tile_size = HEIGHT / self.height;
+    /// Create an empty `width` by `height` grid. Tile value 0 is empty, 5 is
+    /// a door and everything else is a wall.
+    pub fn new(width: i32, height: i32) -> Grid {
+        let size = (width * height) as usize;
+        let mut kinds = vec![TileKind::Wall; 256];
+        kinds[0] = TileKind::Empty;
+        kinds[DOOR as usize] = TileKind::Door;
+        Grid {
+            tiles: vec![0; size],
+            kinds,
+            heights: vec![1.0; 256],
+            segments: vec![None; 256],
+            floor: vec![0; size],
+            ceiling: vec![0; size],
+            floor_heights: vec![0.0; size],
+            ceiling_heights: vec![1.0; size],
+            light: vec![255; size],
+            lights: Vec::new(),
+            light_map: vec![1.0; size],
+            doors: HashMap::new(),
+            portals: HashMap::new(),
+            width,
+            height,
+            tile_size: 1,
+        }
+    }
+
+    /// Load a map from an image, where the red channel of each pixel is the
+    /// tile value and zero is empty space. The green and blue channels are the
+    /// floor and ceiling values of the tile, and alpha is its light level.
+    pub fn load<P: AsRef<Path>>(path: P) -> ImageResult<Grid> {
+        let grid_image = image::open(path)?;
+        let mut grid = Grid::new(grid_image.width() as i32, grid_image.height() as i32);
 
         grid_image.pixels().for_each(|pixel| {
             let x = pixel.0 as usize;
             let y = pixel.1 as usize;
             let i = x + (y * grid_image.width() as usize);
-            self.tiles[i] = pixel.2 .0[0];
+            grid.tiles[i] = pixel.2 .0[0];
+            grid.floor[i] = pixel.2 .0[1];
+            grid.ceiling[i] = pixel.2 .0[2];
+            grid.light[i] = pixel.2 .0[3];
         });
+        grid.find_doors();
+        grid.bake_lights();
+
+        Ok(grid)
+    }
+
+    /// Create the state for every door tile. Doors between walls of any kind
+    /// to their left and right run along the x axis, otherwise they run along
+    /// y.
+    pub fn find_doors(&mut self) {
+        self.doors.clear();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let i = (x + y * self.width) as usize;
+                if self.kind_at(x, y) == TileKind::Door {
+                    let horizontal =
+                        self.kind_at(x - 1, y).is_wall() && self.kind_at(x + 1, y).is_wall();
+                    self.doors.insert(i, Door::new(horizontal));
+                }
+            }
+        }
+    }
+
+    /// Work out how bright each tile is from its base light level and the
+    /// point lights that can see it. Needs doing again after the tiles, light
+    /// levels or lights change. Doors are treated as they are now, so a closed
+    /// door keeps light out even once it's opened.
+    pub fn bake_lights(&mut self) {
+        let mut light_map = Vec::with_capacity(self.tiles.len());
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let base = self.light[(x + y * self.width) as usize] as f32 / 255.0;
+                let lit: f32 = self
+                    .lights
+                    .iter()
+                    .map(|light| light.contribution(self, x, y))
+                    .sum();
+                light_map.push((base + lit).min(1.0));
+            }
+        }
+        self.light_map = light_map;
+    }
+
+    /// Animate any doors that are opening or closing.
+    pub fn update(&mut self, delta: f32) {
+        for door in self.doors.values_mut() {
+            door.update(delta);
+        }
+    }
+
+    /// Open or close the door at `x`, `y`, if there is one.
+    pub fn toggle_door(&mut self, x: i32, y: i32) {
+        if let Some(i) = self.index(x, y) {
+            if let Some(door) = self.doors.get_mut(&i) {
+                door.toggle();
+            }
+        }
+    }
+
+    /// The door at `x`, `y`, if there is one.
+    pub fn door_at(&self, x: i32, y: i32) -> Option<&Door> {
+        self.index(x, y).and_then(|i| self.doors.get(&i))
+    }
+
+    /// The tile value at `x`, `y`, or zero outside the grid.
+    pub fn tile_at(&self, x: i32, y: i32) -> u8 {
+        self.index(x, y).map_or(0, |i| self.tiles[i])
+    }
+
+    /// The kind of the tile at `x`, `y`. Everything outside the grid is wall.
+    pub fn kind_at(&self, x: i32, y: i32) -> TileKind {
+        self.index(x, y)
+            .map_or(TileKind::Wall, |i| self.kinds[self.tiles[i] as usize])
+    }
+
+    /// The thin wall in the tile at `x`, `y`, if it has one.
+    pub fn segment_at(&self, x: i32, y: i32) -> Option<&Segment> {
+        if self.kind_at(x, y) != TileKind::Segment {
+            return None;
+        }
+        self.segments[self.tile_at(x, y) as usize].as_ref()
+    }
+
+    /// Whether walls with tile value `tile` can be seen through.
+    pub fn is_transparent(&self, tile: u8) -> bool {
+        self.kinds[tile as usize] == TileKind::Transparent
+    }
+
+    /// The portal in the tile at `x`, `y`, if it has one.
+    pub fn portal_at(&self, x: i32, y: i32) -> Option<&Portal> {
+        self.index(x, y).and_then(|i| self.portals.get(&i))
+    }
+
+    /// The portal that `portal` leads to, if it is there.
+    pub fn portal_exit(&self, portal: &Portal) -> Option<&Portal> {
+        self.portal_at(portal.link[0], portal.link[1])
+    }
+
+    /// Whether walls with tile value `tile` reflect rays.
+    pub fn is_mirror(&self, tile: u8) -> bool {
+        self.kinds[tile as usize] == TileKind::Mirror
+    }
+
+    /// Height of the top of the tallest wall in the grid, or of the highest
+    /// ceiling if that is higher, in wall heights.
+    pub fn tallest_wall(&self) -> f32 {
+        self.tiles
+            .iter()
+            .zip(&self.floor_heights)
+            .filter(|(tile, _)| !self.kinds[**tile as usize].is_open())
+            .map(|(tile, floor)| floor + self.heights[*tile as usize])
+            .chain(self.ceiling_heights.iter().copied())
+            .fold(0.0, f32::max)
+    }
+
+    /// Whether the whole of the tile at `x`, `y` blocks movement. Everything
+    /// outside the grid is solid. Thin walls only block part of their tile,
+    /// so tiles with them aren't solid. Portals are solid, though their face
+    /// can still be walked into, which collisions check separately.
+    pub fn is_solid(&self, x: i32, y: i32) -> bool {
+        match self.index(x, y) {
+            None => true,
+            Some(i) => match self.doors.get(&i) {
+                Some(door) => !door.is_passable(),
+                None => {
+                    let kind = self.kinds[self.tiles[i] as usize];
+                    !kind.is_open() && kind != TileKind::Segment
+                }
+            },
+        }
+    }
+
+    fn index(&self, x: i32, y: i32) -> Option<usize> {
+        if x >= 0 && x < self.width && y >= 0 && y < self.height {
+            Some((x + y * self.width) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// The floor value of the tile at `x`, `y`, or zero outside the grid.
+    pub fn floor_at(&self, x: i32, y: i32) -> u8 {
+        self.index(x, y).map_or(0, |i| self.floor[i])
+    }
+
+    /// The height of the floor of the tile at `x`, `y`, or zero outside the
+    /// grid.
+    pub fn floor_height_at(&self, x: i32, y: i32) -> f32 {
+        self.index(x, y).map_or(0.0, |i| self.floor_heights[i])
     }
 
-    pub fn draw(&self, frame: &mut [u8]) {
+    /// The height of the ceiling of the tile at `x`, `y`, or one outside the
+    /// grid.
+    pub fn ceiling_height_at(&self, x: i32, y: i32) -> f32 {
+        self.index(x, y).map_or(1.0, |i| self.ceiling_heights[i])
+    }
+
+    /// Whether the floor or ceiling changes height between the tiles `from`
+    /// and `to`.
+    pub fn is_step(&self, from: [i32; 2], to: [i32; 2]) -> bool {
+        let [from_x, from_y] = from;
+        let [to_x, to_y] = to;
+        self.floor_height_at(from_x, from_y) != self.floor_height_at(to_x, to_y)
+            || self.ceiling_height_at(from_x, from_y) != self.ceiling_height_at(to_x, to_y)
+    }
+
+    /// Whether the floor has any holes in it.
+    pub fn has_openings(&self) -> bool {
+        self.floor.contains(&OPENING)
+    }
+
+    /// The ceiling value of the tile at `x`, `y`, or zero outside the grid.
+    pub fn ceiling_at(&self, x: i32, y: i32) -> u8 {
+        self.index(x, y).map_or(0, |i| self.ceiling[i])
+    }
+
+    /// How bright the tile at `x`, `y` is, from 0 to 1. Everything outside
+    /// the grid is fully lit.
+    pub fn light_at(&self, x: i32, y: i32) -> f32 {
+        self.index(x, y).map_or(1.0, |i| self.light_map[i])
+    }
+
+    /// Scale the minimap so the whole grid fits in a `size` pixel square.
+    pub fn fit(&mut self, size: i32) {
+        self.tile_size = (size / self.width.max(self.height)).max(1);
+    }
+
+    pub fn draw(&self, frame: &mut Frame) {
         for (i, grid_value) in self.tiles.iter().enumerate() {
             let x_index = i % self.width as usize;
             let y_index = i / self.width as usize;
-            if *grid_value > 1 {
+            let open_door = self.doors.get(&i).is_some_and(Door::is_passable);
+            let colour = match self.kinds[*grid_value as usize] {
+                TileKind::Empty => continue,
+                TileKind::Transparent => [0, 160, 120, 255],
+                TileKind::Mirror => [160, 220, 255, 255],
+                TileKind::Portal => [255, 140, 0, 255],
+                TileKind::StairsUp => [0, 90, 200, 255],
+                TileKind::StairsDown => [0, 60, 120, 255],
+                TileKind::Wall | TileKind::Door => [0, 255, 0, 255],
+                TileKind::Segment => {
+                    if let Some(segment) = &self.segments[*grid_value as usize] {
+                        let point = |p: [f32; 2]| Point {
+                            x: ((x_index as f32 + p[0]) * self.tile_size as f32) as i32,
+                            y: ((y_index as f32 + p[1]) * self.tile_size as f32) as i32,
+                        };
+                        line(
+                            frame,
+                            &point(segment.start),
+                            &point(segment.end),
+                            [0, 255, 0, 255],
+                        );
+                    }
+                    continue;
+                }
+            };
+            if !open_door {
                 rect_filled(
                     frame,
                     &Point {
@@ -40,27 +373,66 @@ impl Grid {
                         x: x_index as i32 * self.tile_size + self.tile_size,
                         y: y_index as i32 * self.tile_size + self.tile_size,
                     },
-                    [0, 255, 0, 255],
+                    colour,
                 )
             }
         }
 
         let grid_colour = [0, 200, 0, 255];
-        for i in (0..HEIGHT).step_by(self.tile_size as usize) {
+        let map_width = self.width * self.tile_size;
+        let map_height = self.height * self.tile_size;
+        for i in (0..map_height).step_by(self.tile_size as usize) {
             line(
                 frame,
                 &Point { x: 0, y: i },
-                &Point { x: HEIGHT, y: i },
+                &Point { x: map_width, y: i },
                 grid_colour,
             );
         }
-        for i in (0..HEIGHT).step_by(self.tile_size as usize) {
+        for i in (0..map_width).step_by(self.tile_size as usize) {
             line(
                 frame,
                 &Point { x: i, y: 0 },
-                &Point { x: i, y: HEIGHT },
+                &Point {
+                    x: i,
+                    y: map_height,
+                },
                 grid_colour,
             );
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 3 by 3 grid with a door in the middle between tiles of `kind` on
+    /// two opposite sides, left and right if `horizontal`.
+    fn door_between(kind: TileKind, horizontal: bool) -> Grid {
+        let mut grid = Grid::new(3, 3);
+        grid.kinds[1] = kind;
+        let sides = if horizontal { [3, 5] } else { [1, 7] };
+        for side in sides {
+            grid.tiles[side] = 1;
+        }
+        grid.tiles[4] = DOOR;
+        grid.find_doors();
+        grid
+    }
+
+    #[test]
+    fn doors_run_between_any_walls() {
+        for kind in [
+            TileKind::Wall,
+            TileKind::Transparent,
+            TileKind::Mirror,
+            TileKind::Portal,
+        ] {
+            let grid = door_between(kind, true);
+            assert!(grid.door_at(1, 1).unwrap().horizontal, "{:?}", kind);
+            let grid = door_between(kind, false);
+            assert!(!grid.door_at(1, 1).unwrap().horizontal, "{:?}", kind);
+        }
+    }
+}
@@ -0,0 +1,1181 @@
+//! Loading levels from files.
+//!
+//! Levels are written in [RON](https://github.com/ron-rs/ron). The tile
+//! layers are drawn as rows of characters, and a legend says what each
+//! character means:
+//!
+//! ```ron
+//! Level(
+//!     name: "Example",
+//!     author: "Someone",
+//!     // Texture names and image paths, relative to the level file.
+//!     textures: {
+//!         "brick": "../brick_2.png",
+//!         "door": "../door.png",
+//!         "floor": "../floor.png",
+//!         "barrel": "../barrel.png",
+//!         "grate": "../grate.png",
+//!         "mirror": "../mirror.png",
+//!         "portal": "../portal.png",
+//!         "sky": "../sky.png",
+//!     },
+//!     // Animated textures, which can be used anywhere a texture can. The
+//!     // frames are either side by side in one image or in separate files.
+//!     animations: {
+//!         "water": Sheet(path: "../water.png", frames: 8, frame_rate: 10.0),
+//!         "screen": Frames(paths: ["../screen_0.png", "../screen_1.png"], frame_rate: 4.0),
+//!     },
+//!     // What each character in `tiles` is.
+//!     legend: {
+//!         '.': Empty,
+//!         '#': Wall("brick"),
+//!         'd': Door("door"),
+//!         // Walls of other heights, in wall heights. The player can see over
+//!         // low walls, and tall ones stick up above the rest.
+//!         'l': Block(texture: "brick", height: 0.5),
+//!         // A solid wall that can be seen through where its texture is
+//!         // transparent.
+//!         'g': Transparent("grate"),
+//!         // A wall that reflects, tinted by its texture where that is
+//!         // partly transparent.
+//!         'M': Mirror("mirror"),
+//!         // A pair of portals. Walking or looking into the face of one
+//!         // comes out of the face of the other, which must appear exactly
+//!         // once in `tiles`. Both faces need an open tile in front of them.
+//!         'A': Portal(texture: "portal", facing: East, link: 'B'),
+//!         'B': Portal(texture: "portal", facing: South, link: 'A'),
+//!         // A thin wall between two points in the tile, from (0, 0) at its
+//!         // top left to (1, 1) at its bottom right. This one runs corner to
+//!         // corner.
+//!         '/': Segment(texture: "brick", start: (0.0, 1.0), end: (1.0, 0.0)),
+//!         // Stairs, which take the player to the same tile of the storey
+//!         // above or below when they step onto them. That tile must be open.
+//!         'U': StairsUp,
+//!         'D': StairsDown,
+//!     },
+//!     tiles: [
+//!         "##B##",
+//!         "A..U#",
+//!         "##d##",
+//!     ],
+//!     // Optional floor and ceiling layers, the same size as `tiles`. A `.`
+//!     // is an untextured floor or a ceiling open to the sky, and a space in
+//!     // the floor is a hole down to an open tile of the storey below,
+//!     // which the player falls through unless they jump over it.
+//!     floors: { 'f': "floor" },
+//!     floor: [
+//!         ".....",
+//!         ".fff.",
+//!         ".....",
+//!     ],
+//!     ceilings: {},
+//!     ceiling: [],
+//!     // Optional heights of the floor and ceiling of each tile, in wall
+//!     // heights, for steps, pits and low ceilings. A `.` is 0 for floors and
+//!     // 1 for ceilings. The player can step up to 0.3 without jumping.
+//!     floor_heights: { 's': 0.2, 'p': -0.3 },
+//!     floor_height: [
+//!         ".....",
+//!         ".sp..",
+//!         ".....",
+//!     ],
+//!     ceiling_heights: { 'l': 0.8 },
+//!     ceiling_height: [
+//!         ".....",
+//!         "..l..",
+//!         ".....",
+//!     ],
+//!     // Optional light level of each tile, from 0 for pitch black to 9 for
+//!     // fully lit. Defaults to fully lit.
+//!     light: [
+//!         "99999",
+//!         "93339",
+//!         "99999",
+//!     ],
+//!     // Point lights, brightening tiles they can see within `radius` tiles
+//!     // by up to `intensity` (1 by default).
+//!     lights: [
+//!         (x: 1.5, y: 1.5, radius: 3.0, intensity: 0.8),
+//!     ],
+//!     // Optional storeys stacked above the one given by the layers above,
+//!     // with the same size and legends. Each has its own layers, any of
+//!     // which but `tiles` can be left out.
+//!     storeys: [
+//!         (
+//!             tiles: [
+//!                 "#####",
+//!                 "#..D#",
+//!                 "#####",
+//!             ],
+//!             floor: [
+//!                 ".....",
+//!                 ".  f.",
+//!                 ".....",
+//!             ],
+//!             lights: [],
+//!         ),
+//!     ],
+//!     // Where the player starts, in tiles, facing `angle` radians
+//!     // anticlockwise from the x axis, on `storey` counting up from 0.
+//!     // Defaults to the centre of the bottom storey.
+//!     spawn: Some((x: 1.5, y: 1.5, angle: 0.0, storey: 0)),
+//!     // Sprites are on the bottom storey unless given a `storey`.
+//!     sprites: [
+//!         (x: 2.5, y: 1.5, texture: "barrel"),
+//!         (x: 3.5, y: 1.5, texture: "barrel", storey: 1),
+//!     ],
+//!     // How far the player can see, in tiles. Defaults to 10.
+//!     view_distance: 12.0,
+//!     // Optional distance fog: `Linear(start: 4.0, end: 12.0)`,
+//!     // `Exponential(density: 0.2)` or `None`, fading to `colour`.
+//!     fog: (mode: Linear(start: 4.0, end: 12.0), colour: (0, 0, 0)),
+//!     // Optional panoramic texture drawn where there's no ceiling, wrapping
+//!     // once around the player from the horizon up.
+//!     sky: Some("sky"),
+//! )
+//! ```
+//!
+//! PNG maps, as read by [`Grid::load`], can be loaded as levels too. They use
+//! the default textures and have no sprites.
+
+use crate::door::DOOR;
+use crate::fog::Fog;
+use crate::grid::{Grid, Segment, TileKind, OPENING};
+use crate::light::Light;
+use crate::portal::Portal;
+use crate::sprite::Sprite;
+use crate::texture::{Texture, TextureTable};
+use crate::viewport::DEFAULT_VIEW_DISTANCE;
+use image::ImageError;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Where the player starts in a level.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct Spawn {
+    pub x: f32,
+    pub y: f32,
+    #[serde(default)]
+    pub angle: f32,
+    /// Which storey the player starts on, counting up from 0.
+    #[serde(default)]
+    pub storey: usize,
+}
+
+/// A loaded level, ready to be handed to the `World`.
+pub struct Level {
+    pub name: String,
+    pub author: String,
+    /// The storeys of the level from the bottom up. There is always at least
+    /// one.
+    pub storeys: Vec<Grid>,
+    pub textures: TextureTable,
+    pub spawn: Spawn,
+    pub sprites: Vec<Sprite>,
+    /// How far the player can see, in tiles.
+    pub view_distance: f32,
+    pub fog: Fog,
+    /// Index of the sky texture in `textures`.
+    pub sky: Option<usize>,
+}
+
+/// Why a level couldn't be loaded. Errors don't include the path of the
+/// level itself, which the caller already knows, only of the textures it
+/// uses.
+#[derive(Debug)]
+pub enum LevelError {
+    Io(std::io::Error),
+    Parse(ron::error::SpannedError),
+    /// A PNG map that couldn't be read.
+    Map(ImageError),
+    /// A texture that couldn't be read.
+    Image {
+        path: PathBuf,
+        source: ImageError,
+    },
+    /// A texture name that isn't in the level's `textures` or `animations`.
+    UnknownTexture(String),
+    /// A sprite sheet that can't be split into the number of frames given.
+    SheetSize(String),
+    /// An animation without any frames.
+    NoFrames(String),
+    /// A character in a layer that isn't in its legend.
+    UnknownTile {
+        layer: &'static str,
+        symbol: char,
+        x: usize,
+        y: usize,
+    },
+    /// A layer that isn't the same size as the `tiles` layer.
+    LayerSize(&'static str),
+    /// More distinct tiles than fit in a tile value.
+    TooManyTiles(&'static str),
+    /// A portal whose link isn't a portal appearing exactly once on the
+    /// same storey.
+    PortalLink(char),
+    /// A portal whose face is up against something solid, which the player
+    /// would come out inside.
+    PortalBlocked(char),
+    /// A spawn point or sprite on a storey the level doesn't have.
+    NoStorey(usize),
+    /// A hole in the floor of `storey` over a tile on the storey below that
+    /// the player couldn't land in.
+    BlockedHole {
+        storey: usize,
+        x: usize,
+        y: usize,
+    },
+    /// Stairs on `storey` leading to a tile on the next storey up or down
+    /// that the player couldn't stand in.
+    BlockedStairs {
+        storey: usize,
+        x: usize,
+        y: usize,
+    },
+    /// A view distance that isn't a positive number of tiles.
+    ViewDistance(f32),
+    /// A level without any tiles.
+    Empty,
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LevelError::Io(source) => write!(f, "{}", source),
+            LevelError::Parse(source) => write!(f, "{}", source),
+            LevelError::Map(source) => write!(f, "{}", source),
+            LevelError::Image { path, source } => write!(f, "{}: {}", path.display(), source),
+            LevelError::UnknownTexture(name) => write!(f, "unknown texture `{}`", name),
+            LevelError::SheetSize(name) => write!(
+                f,
+                "the sprite sheet for `{}` isn't a whole number of frames wide",
+                name
+            ),
+            LevelError::NoFrames(name) => write!(f, "the animation `{}` has no frames", name),
+            LevelError::UnknownTile {
+                layer,
+                symbol,
+                x,
+                y,
+            } => write!(
+                f,
+                "`{}` at {}, {} in the {} layer isn't in its legend",
+                symbol, x, y, layer
+            ),
+            LevelError::LayerSize(layer) => {
+                write!(f, "the {} layer isn't the same size as the tiles", layer)
+            }
+            LevelError::TooManyTiles(layer) => {
+                write!(f, "the {} layer has more than 255 kinds of tile", layer)
+            }
+            LevelError::PortalLink(symbol) => write!(
+                f,
+                "portal `{}` must link to a portal that appears exactly once on its storey",
+                symbol
+            ),
+            LevelError::PortalBlocked(symbol) => {
+                write!(f, "portal `{}` faces into something solid", symbol)
+            }
+            LevelError::NoStorey(storey) => write!(f, "the level has no storey {}", storey),
+            LevelError::BlockedHole { storey, x, y } => write!(
+                f,
+                "the hole at {}, {} on storey {} is over something solid",
+                x, y, storey
+            ),
+            LevelError::BlockedStairs { storey, x, y } => write!(
+                f,
+                "the stairs at {}, {} on storey {} lead into something solid",
+                x, y, storey
+            ),
+            LevelError::ViewDistance(distance) => write!(
+                f,
+                "the view distance must be a positive number of tiles, not {}",
+                distance
+            ),
+            LevelError::Empty => write!(f, "the level has no tiles"),
+        }
+    }
+}
+
+impl Error for LevelError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LevelError::Io(source) => Some(source),
+            LevelError::Parse(source) => Some(source),
+            LevelError::Map(source) => Some(source),
+            LevelError::Image { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "Level")]
+struct LevelFile {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    author: String,
+    #[serde(default)]
+    textures: BTreeMap<String, String>,
+    #[serde(default)]
+    animations: BTreeMap<String, AnimationDef>,
+    legend: BTreeMap<char, TileDef>,
+    tiles: Vec<String>,
+    #[serde(default)]
+    floors: BTreeMap<char, String>,
+    #[serde(default)]
+    floor: Vec<String>,
+    #[serde(default)]
+    ceilings: BTreeMap<char, String>,
+    #[serde(default)]
+    ceiling: Vec<String>,
+    #[serde(default)]
+    floor_heights: BTreeMap<char, f32>,
+    #[serde(default)]
+    floor_height: Vec<String>,
+    #[serde(default)]
+    ceiling_heights: BTreeMap<char, f32>,
+    #[serde(default)]
+    ceiling_height: Vec<String>,
+    #[serde(default)]
+    light: Vec<String>,
+    #[serde(default)]
+    lights: Vec<Light>,
+    #[serde(default)]
+    storeys: Vec<StoreyDef>,
+    #[serde(default)]
+    spawn: Option<Spawn>,
+    #[serde(default)]
+    sprites: Vec<SpriteDef>,
+    #[serde(default = "default_view_distance")]
+    view_distance: f32,
+    #[serde(default)]
+    fog: Fog,
+    #[serde(default)]
+    sky: Option<String>,
+}
+
+/// The layers of a storey above the first, which share the first storey's
+/// size and legends.
+#[derive(Deserialize)]
+struct StoreyDef {
+    tiles: Vec<String>,
+    #[serde(default)]
+    floor: Vec<String>,
+    #[serde(default)]
+    ceiling: Vec<String>,
+    #[serde(default)]
+    floor_height: Vec<String>,
+    #[serde(default)]
+    ceiling_height: Vec<String>,
+    #[serde(default)]
+    light: Vec<String>,
+    #[serde(default)]
+    lights: Vec<Light>,
+}
+
+fn default_view_distance() -> f32 {
+    DEFAULT_VIEW_DISTANCE
+}
+
+/// An entry in the tile legend.
+#[derive(Deserialize)]
+enum TileDef {
+    Empty,
+    Wall(String),
+    Door(String),
+    Block {
+        texture: String,
+        height: f32,
+    },
+    Transparent(String),
+    Mirror(String),
+    Segment {
+        texture: String,
+        start: [f32; 2],
+        end: [f32; 2],
+    },
+    Portal {
+        texture: String,
+        facing: Facing,
+        link: char,
+    },
+    StairsUp,
+    StairsDown,
+}
+
+/// Which way a portal's face points, with north at the top of the map.
+#[derive(Copy, Clone, Deserialize)]
+enum Facing {
+    North,
+    East,
+    South,
+    West,
+}
+
+impl Facing {
+    /// Unit vector pointing this way, with y pointing down the map.
+    fn normal(self) -> [i32; 2] {
+        match self {
+            Facing::North => [0, -1],
+            Facing::East => [1, 0],
+            Facing::South => [0, 1],
+            Facing::West => [-1, 0],
+        }
+    }
+}
+
+/// An animated texture.
+#[derive(Deserialize)]
+enum AnimationDef {
+    /// Frames side by side in a single image.
+    Sheet {
+        path: String,
+        frames: usize,
+        frame_rate: f32,
+    },
+    /// Each frame in its own image.
+    Frames { paths: Vec<String>, frame_rate: f32 },
+}
+
+#[derive(Deserialize)]
+struct SpriteDef {
+    x: f32,
+    y: f32,
+    texture: String,
+    #[serde(default)]
+    storey: usize,
+}
+
+impl Level {
+    /// Load a level file, or a PNG map if `path` ends in `.png`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Level, LevelError> {
+        let path = path.as_ref();
+        let is_png = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("png"));
+        if is_png {
+            return Level::from_png(path);
+        }
+
+        let source = std::fs::read_to_string(path).map_err(LevelError::Io)?;
+        Level::parse(&source, path.parent().unwrap_or_else(|| Path::new("")))
+    }
+
+    /// Read a level from the contents of a level file, with texture paths
+    /// relative to `base`.
+    pub fn parse(source: &str, base: &Path) -> Result<Level, LevelError> {
+        let file: LevelFile = ron::from_str(source).map_err(LevelError::Parse)?;
+        Level::build(file, base)
+    }
+
+    /// Import a PNG map with the default textures.
+    pub fn from_png<P: AsRef<Path>>(path: P) -> Result<Level, LevelError> {
+        let path = path.as_ref();
+        let grid = Grid::load(path).map_err(LevelError::Map)?;
+        Ok(Level {
+            name: path
+                .file_stem()
+                .map_or_else(String::new, |s| s.to_string_lossy().into_owned()),
+            author: String::new(),
+            spawn: Spawn {
+                x: grid.width as f32 / 2.0,
+                y: grid.height as f32 / 2.0,
+                angle: 0.0,
+                storey: 0,
+            },
+            storeys: vec![grid],
+            textures: default_textures()?,
+            sprites: Vec::new(),
+            view_distance: DEFAULT_VIEW_DISTANCE,
+            fog: Fog::none(),
+            sky: None,
+        })
+    }
+
+    fn build(file: LevelFile, base: &Path) -> Result<Level, LevelError> {
+        let height = file.tiles.len();
+        let width = file.tiles.first().map_or(0, |row| row.chars().count());
+        if width == 0 {
+            return Err(LevelError::Empty);
+        }
+        if !(file.view_distance.is_finite() && file.view_distance > 0.0) {
+            return Err(LevelError::ViewDistance(file.view_distance));
+        }
+
+        let mut textures = TextureTable::new();
+        let mut texture_indices = HashMap::new();
+        for (name, texture_path) in &file.textures {
+            let path = base.join(texture_path);
+            let texture =
+                Texture::load(&path).map_err(|source| LevelError::Image { path, source })?;
+            texture_indices.insert(name.as_str(), textures.add(texture));
+        }
+        let load = |texture_path: &String| {
+            let path = base.join(texture_path);
+            Texture::load(&path).map_err(|source| LevelError::Image { path, source })
+        };
+        for (name, def) in &file.animations {
+            let (frames, frame_rate) = match def {
+                AnimationDef::Sheet {
+                    path,
+                    frames,
+                    frame_rate,
+                } => {
+                    let sheet = load(path)?;
+                    let frames = sheet
+                        .split(*frames)
+                        .ok_or_else(|| LevelError::SheetSize(name.clone()))?;
+                    (frames, *frame_rate)
+                }
+                AnimationDef::Frames { paths, frame_rate } => {
+                    let frames = paths.iter().map(load).collect::<Result<Vec<_>, _>>()?;
+                    (frames, *frame_rate)
+                }
+            };
+            if frames.is_empty() {
+                return Err(LevelError::NoFrames(name.clone()));
+            }
+            texture_indices.insert(name.as_str(), textures.add_animation(frames, frame_rate));
+        }
+        let texture = |name: &str| {
+            texture_indices
+                .get(name)
+                .copied()
+                .ok_or_else(|| LevelError::UnknownTexture(name.to_string()))
+        };
+
+        // Every storey shares the legends, so the tile values are filled in
+        // once and each storey starts from a copy of this.
+        let mut template = Grid::new(width as i32, height as i32);
+
+        // Tile values are handed out in legend order, with 0 for empty space.
+        let mut tile_values = HashMap::new();
+        let mut next_value = 1;
+        for (symbol, def) in &file.legend {
+            let (kind, name, height, segment) = match def {
+                TileDef::Empty => {
+                    tile_values.insert(*symbol, 0);
+                    continue;
+                }
+                TileDef::Wall(name) => (TileKind::Wall, Some(name), 1.0, None),
+                TileDef::Door(name) => (TileKind::Door, Some(name), 1.0, None),
+                TileDef::Block { texture, height } => {
+                    (TileKind::Wall, Some(texture), *height, None)
+                }
+                TileDef::Transparent(name) => (TileKind::Transparent, Some(name), 1.0, None),
+                TileDef::Mirror(name) => (TileKind::Mirror, Some(name), 1.0, None),
+                TileDef::Segment {
+                    texture,
+                    start,
+                    end,
+                } => {
+                    let segment = Segment {
+                        start: *start,
+                        end: *end,
+                    };
+                    (TileKind::Segment, Some(texture), 1.0, Some(segment))
+                }
+                TileDef::Portal { texture, .. } => (TileKind::Portal, Some(texture), 1.0, None),
+                TileDef::StairsUp => (TileKind::StairsUp, None, 1.0, None),
+                TileDef::StairsDown => (TileKind::StairsDown, None, 1.0, None),
+            };
+            if next_value > u8::MAX as usize {
+                return Err(LevelError::TooManyTiles("tiles"));
+            }
+            let value = next_value as u8;
+            next_value += 1;
+            template.kinds[value as usize] = kind;
+            template.heights[value as usize] = height;
+            template.segments[value as usize] = segment;
+            if let Some(name) = name {
+                textures.set_wall(value, texture(name)?);
+            }
+            tile_values.insert(*symbol, value);
+        }
+
+        let mut floor_values = surface_values(&file.floors, "floor")?;
+        for (symbol, name) in &file.floors {
+            textures.set_floor(floor_values[symbol], texture(name)?);
+        }
+        floor_values.entry(' ').or_insert(OPENING);
+
+        let ceiling_values = surface_values(&file.ceilings, "ceiling")?;
+        for (symbol, name) in &file.ceilings {
+            textures.set_ceiling(ceiling_values[symbol], texture(name)?);
+        }
+
+        // Heights default to a flat floor and ceiling a wall height apart.
+        let mut floor_heights: HashMap<char, f32> = file.floor_heights.into_iter().collect();
+        floor_heights.entry('.').or_insert(0.0);
+        let mut ceiling_heights: HashMap<char, f32> = file.ceiling_heights.into_iter().collect();
+        ceiling_heights.entry('.').or_insert(1.0);
+        let values = LayerValues {
+            tiles: tile_values,
+            floor: floor_values,
+            ceiling: ceiling_values,
+            floor_heights,
+            ceiling_heights,
+        };
+
+        // The layers at the top of the file are the bottom storey.
+        let ground = StoreyDef {
+            tiles: file.tiles,
+            floor: file.floor,
+            ceiling: file.ceiling,
+            floor_height: file.floor_height,
+            ceiling_height: file.ceiling_height,
+            light: file.light,
+            lights: file.lights,
+        };
+        let storeys = std::iter::once(ground)
+            .chain(file.storeys)
+            .map(|storey| build_storey(&template, storey, &file.legend, &values))
+            .collect::<Result<Vec<_>, _>>()?;
+        check_landings(&storeys)?;
+
+        let sprites = file
+            .sprites
+            .iter()
+            .map(|s| {
+                if s.storey >= storeys.len() {
+                    return Err(LevelError::NoStorey(s.storey));
+                }
+                Ok(Sprite {
+                    storey: s.storey,
+                    ..Sprite::new(s.x, s.y, texture(&s.texture)?)
+                })
+            })
+            .collect::<Result<Vec<_>, LevelError>>()?;
+        let sky = file.sky.as_deref().map(texture).transpose()?;
+        let spawn = file.spawn.unwrap_or(Spawn {
+            x: width as f32 / 2.0,
+            y: height as f32 / 2.0,
+            angle: 0.0,
+            storey: 0,
+        });
+        if spawn.storey >= storeys.len() {
+            return Err(LevelError::NoStorey(spawn.storey));
+        }
+
+        Ok(Level {
+            name: file.name,
+            author: file.author,
+            spawn,
+            storeys,
+            textures,
+            sprites,
+            view_distance: file.view_distance,
+            fog: file.fog,
+            sky,
+        })
+    }
+}
+
+/// Check that every hole in a floor above the bottom storey, and every flight
+/// of stairs with a storey to lead to, puts the player in an open tile on the
+/// storey above or below.
+fn check_landings(storeys: &[Grid]) -> Result<(), LevelError> {
+    for (storey, pair) in storeys.windows(2).enumerate() {
+        let [below, above] = [&pair[0], &pair[1]];
+        for y in 0..above.height {
+            for x in 0..above.width {
+                let (tile_x, tile_y) = (x as usize, y as usize);
+                if above.floor_at(x, y) == OPENING && !below.kind_at(x, y).is_open() {
+                    return Err(LevelError::BlockedHole {
+                        storey: storey + 1,
+                        x: tile_x,
+                        y: tile_y,
+                    });
+                }
+                if below.kind_at(x, y) == TileKind::StairsUp && !above.kind_at(x, y).is_open() {
+                    return Err(LevelError::BlockedStairs {
+                        storey,
+                        x: tile_x,
+                        y: tile_y,
+                    });
+                }
+                if above.kind_at(x, y) == TileKind::StairsDown && !below.kind_at(x, y).is_open() {
+                    return Err(LevelError::BlockedStairs {
+                        storey: storey + 1,
+                        x: tile_x,
+                        y: tile_y,
+                    });
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What each character means in each of a storey's layers.
+struct LayerValues {
+    tiles: HashMap<char, u8>,
+    floor: HashMap<char, u8>,
+    ceiling: HashMap<char, u8>,
+    floor_heights: HashMap<char, f32>,
+    ceiling_heights: HashMap<char, f32>,
+}
+
+/// Build one storey from its layers, starting from a copy of `template`,
+/// which has everything that comes from the legend.
+fn build_storey(
+    template: &Grid,
+    storey: StoreyDef,
+    legend: &BTreeMap<char, TileDef>,
+    values: &LayerValues,
+) -> Result<Grid, LevelError> {
+    let width = template.width as usize;
+    let mut grid = template.clone();
+    fill_layer(
+        &mut grid.tiles,
+        "tiles",
+        &storey.tiles,
+        width,
+        &values.tiles,
+    )?;
+
+    // Each portal leads to the only tile of the portal it links to.
+    let positions = |symbol: char| {
+        storey.tiles.iter().enumerate().flat_map(move |(y, row)| {
+            row.chars()
+                .enumerate()
+                .filter(move |(_, c)| *c == symbol)
+                .map(move |(x, _)| [x as i32, y as i32])
+        })
+    };
+    for (symbol, def) in legend {
+        if let TileDef::Portal { facing, link, .. } = def {
+            let mut tiles = positions(*symbol).peekable();
+            if tiles.peek().is_none() {
+                continue;
+            }
+            let exits = positions(*link).collect::<Vec<_>>();
+            let links_to_portal = matches!(legend.get(link), Some(TileDef::Portal { .. }));
+            if !links_to_portal || exits.len() != 1 {
+                return Err(LevelError::PortalLink(*symbol));
+            }
+            for tile in tiles {
+                let facing = facing.normal();
+                let [x, y] = [tile[0] + facing[0], tile[1] + facing[1]];
+                if !grid.kind_at(x, y).is_open() {
+                    return Err(LevelError::PortalBlocked(*symbol));
+                }
+                let portal = Portal {
+                    tile,
+                    facing,
+                    link: exits[0],
+                };
+                grid.portals
+                    .insert((tile[0] + tile[1] * width as i32) as usize, portal);
+            }
+        }
+    }
+
+    if !storey.floor.is_empty() {
+        fill_layer(
+            &mut grid.floor,
+            "floor",
+            &storey.floor,
+            width,
+            &values.floor,
+        )?;
+    }
+    if !storey.ceiling.is_empty() {
+        fill_layer(
+            &mut grid.ceiling,
+            "ceiling",
+            &storey.ceiling,
+            width,
+            &values.ceiling,
+        )?;
+    }
+    if !storey.floor_height.is_empty() {
+        fill_layer(
+            &mut grid.floor_heights,
+            "floor height",
+            &storey.floor_height,
+            width,
+            &values.floor_heights,
+        )?;
+    }
+    if !storey.ceiling_height.is_empty() {
+        fill_layer(
+            &mut grid.ceiling_heights,
+            "ceiling height",
+            &storey.ceiling_height,
+            width,
+            &values.ceiling_heights,
+        )?;
+    }
+    if !storey.light.is_empty() {
+        fill_layer(
+            &mut grid.light,
+            "light",
+            &storey.light,
+            width,
+            &light_values(),
+        )?;
+    }
+
+    grid.find_doors();
+    grid.lights = storey.lights;
+    grid.bake_lights();
+    Ok(grid)
+}
+
+/// Hand out floor or ceiling values in legend order, with `.` meaning none.
+/// The last value is kept back for [`OPENING`].
+fn surface_values(
+    legend: &BTreeMap<char, String>,
+    layer: &'static str,
+) -> Result<HashMap<char, u8>, LevelError> {
+    if legend.len() >= u8::MAX as usize {
+        return Err(LevelError::TooManyTiles(layer));
+    }
+    let mut values: HashMap<char, u8> = legend
+        .keys()
+        .enumerate()
+        .map(|(i, symbol)| (*symbol, i as u8 + 1))
+        .collect();
+    values.entry('.').or_insert(0);
+    Ok(values)
+}
+
+/// Light levels `0` to `9`, spread out over the full range of a tile value.
+fn light_values() -> HashMap<char, u8> {
+    ('0'..='9')
+        .zip((0..=9).map(|level| (level * 255 / 9) as u8))
+        .collect()
+}
+
+/// Translate the rows of characters in a layer into tile values.
+fn fill_layer<T: Copy>(
+    layer: &mut [T],
+    name: &'static str,
+    rows: &[String],
+    width: usize,
+    values: &HashMap<char, T>,
+) -> Result<(), LevelError> {
+    if rows.len() * width != layer.len() {
+        return Err(LevelError::LayerSize(name));
+    }
+    for (y, row) in rows.iter().enumerate() {
+        if row.chars().count() != width {
+            return Err(LevelError::LayerSize(name));
+        }
+        for (x, symbol) in row.chars().enumerate() {
+            layer[x + y * width] = *values.get(&symbol).ok_or(LevelError::UnknownTile {
+                layer: name,
+                symbol,
+                x,
+                y,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// The textures used for PNG maps. Tile values 1 and 255 are brick, 2 is
+/// stone, 3 is wood, 4 is metal and 5 is a door.
+fn default_textures() -> Result<TextureTable, LevelError> {
+    let mut textures = TextureTable::new();
+    let mut load = |path: &str| {
+        Texture::load(path)
+            .map(|texture| textures.add(texture))
+            .map_err(|source| LevelError::Image {
+                path: PathBuf::from(path),
+                source,
+            })
+    };
+    let brick = load("assets/brick_2.png")?;
+    let stone = load("assets/stone.png")?;
+    let wood = load("assets/wood.png")?;
+    let metal = load("assets/metal.png")?;
+    let door = load("assets/door.png")?;
+    let floor = load("assets/floor.png")?;
+    let ceiling = load("assets/ceiling.png")?;
+
+    textures.set_wall(1, brick);
+    textures.set_wall(2, stone);
+    textures.set_wall(3, wood);
+    textures.set_wall(4, metal);
+    textures.set_wall(DOOR, door);
+    textures.set_wall(255, brick);
+    textures.set_floor(1, floor);
+    textures.set_floor(2, wood);
+    textures.set_ceiling(1, ceiling);
+    textures.set_ceiling(2, wood);
+    Ok(textures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse a level with textures relative to the levels directory.
+    fn parse(source: &str) -> Result<Level, LevelError> {
+        Level::parse(source, Path::new("assets/levels"))
+    }
+
+    #[test]
+    fn parses_a_level() {
+        let level = parse(
+            r#"Level(
+                name: "Test",
+                textures: { "brick": "../brick_2.png" },
+                legend: { '.': Empty, 'w': Wall("brick"), 'l': Block(texture: "brick", height: 0.5) },
+                tiles: [
+                    "wwww",
+                    "w.lw",
+                    "wwww",
+                ],
+                floor_heights: { 's': 0.2 },
+                floor_height: [
+                    "....",
+                    ".s..",
+                    "....",
+                ],
+                spawn: Some((x: 1.5, y: 1.5, angle: 1.0)),
+            )"#,
+        )
+        .unwrap();
+        assert_eq!(level.name, "Test");
+        assert_eq!(level.storeys.len(), 1);
+        let grid = &level.storeys[0];
+        assert_eq!((grid.width, grid.height), (4, 3));
+        assert_eq!(grid.kind_at(0, 0), TileKind::Wall);
+        assert_eq!(grid.kind_at(1, 1), TileKind::Empty);
+        assert_eq!(grid.heights[grid.tile_at(2, 1) as usize], 0.5);
+        assert_eq!(grid.floor_height_at(1, 1), 0.2);
+        assert_eq!(grid.ceiling_height_at(1, 1), 1.0);
+        assert_eq!(
+            (level.spawn.x, level.spawn.y, level.spawn.angle),
+            (1.5, 1.5, 1.0)
+        );
+    }
+
+    #[test]
+    fn missing_file() {
+        let result = Level::load("assets/levels/missing.ron");
+        assert!(matches!(result, Err(LevelError::Io(_))));
+    }
+
+    #[test]
+    fn missing_map() {
+        let result = Level::load("assets/missing.png");
+        assert!(matches!(result, Err(LevelError::Map(_))));
+    }
+
+    #[test]
+    fn bad_syntax() {
+        let result = parse("Level(legend: {}, tiles: [");
+        assert!(matches!(result, Err(LevelError::Parse(_))));
+    }
+
+    #[test]
+    fn missing_texture_file() {
+        let result = parse(
+            r#"Level(
+                textures: { "brick": "../missing.png" },
+                legend: { 'w': Wall("brick") },
+                tiles: ["w"],
+            )"#,
+        );
+        assert!(matches!(result, Err(LevelError::Image { .. })));
+    }
+
+    #[test]
+    fn unknown_texture() {
+        let result = parse(r#"Level(legend: { 'w': Wall("brick") }, tiles: ["w"])"#);
+        assert!(matches!(result, Err(LevelError::UnknownTexture(name)) if name == "brick"));
+    }
+
+    #[test]
+    fn sheet_not_whole_frames() {
+        let result = parse(
+            r#"Level(
+                animations: { "water": Sheet(path: "../water.png", frames: 0, frame_rate: 1.0) },
+                legend: { '.': Empty },
+                tiles: ["."],
+            )"#,
+        );
+        assert!(matches!(result, Err(LevelError::SheetSize(name)) if name == "water"));
+    }
+
+    #[test]
+    fn animation_without_frames() {
+        let result = parse(
+            r#"Level(
+                animations: { "screen": Frames(paths: [], frame_rate: 1.0) },
+                legend: { '.': Empty },
+                tiles: ["."],
+            )"#,
+        );
+        assert!(matches!(result, Err(LevelError::NoFrames(name)) if name == "screen"));
+    }
+
+    #[test]
+    fn unknown_tile() {
+        let result = parse(r#"Level(legend: { '.': Empty }, tiles: ["..", ".x"])"#);
+        assert!(matches!(
+            result,
+            Err(LevelError::UnknownTile {
+                layer: "tiles",
+                symbol: 'x',
+                x: 1,
+                y: 1,
+            })
+        ));
+    }
+
+    #[test]
+    fn layer_size() {
+        let result = parse(r#"Level(legend: { '.': Empty }, tiles: ["..", ".."], light: ["99"])"#);
+        assert!(matches!(result, Err(LevelError::LayerSize("light"))));
+
+        let result = parse(r#"Level(legend: { '.': Empty }, tiles: ["..", "."])"#);
+        assert!(matches!(result, Err(LevelError::LayerSize("tiles"))));
+    }
+
+    #[test]
+    fn too_many_tiles() {
+        let floors = (0..255)
+            .map(|i| format!("'{}': \"floor\"", char::from_u32(0x100 + i).unwrap()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let source = format!(
+            r#"Level(legend: {{ '.': Empty }}, tiles: ["."], floors: {{ {} }})"#,
+            floors
+        );
+        assert!(matches!(
+            parse(&source),
+            Err(LevelError::TooManyTiles("floor"))
+        ));
+    }
+
+    #[test]
+    fn portal_without_partner() {
+        let result = parse(
+            r#"Level(
+                textures: { "portal": "../portal.png" },
+                legend: {
+                    '.': Empty,
+                    'A': Portal(texture: "portal", facing: East, link: 'B'),
+                    'B': Portal(texture: "portal", facing: West, link: 'A'),
+                },
+                tiles: ["A.."],
+            )"#,
+        );
+        assert!(matches!(result, Err(LevelError::PortalLink('A'))));
+    }
+
+    #[test]
+    fn portal_facing_a_wall() {
+        let result = parse(
+            r#"Level(
+                textures: { "portal": "../portal.png" },
+                legend: {
+                    '.': Empty,
+                    'A': Portal(texture: "portal", facing: East, link: 'B'),
+                    'B': Portal(texture: "portal", facing: East, link: 'A'),
+                },
+                tiles: [".A.B"],
+            )"#,
+        );
+        assert!(matches!(result, Err(LevelError::PortalBlocked('B'))));
+    }
+
+    #[test]
+    fn spawn_on_missing_storey() {
+        let result = parse(
+            r#"Level(
+                legend: { '.': Empty },
+                tiles: ["."],
+                spawn: Some((x: 0.5, y: 0.5, storey: 1)),
+            )"#,
+        );
+        assert!(matches!(result, Err(LevelError::NoStorey(1))));
+    }
+
+    #[test]
+    fn hole_over_a_wall() {
+        let result = parse(
+            r#"Level(
+                textures: { "brick": "../brick_2.png" },
+                legend: { '.': Empty, 'w': Wall("brick") },
+                tiles: ["..w"],
+                storeys: [(tiles: ["..."], floor: [".  "])],
+            )"#,
+        );
+        assert!(matches!(
+            result,
+            Err(LevelError::BlockedHole {
+                storey: 1,
+                x: 2,
+                y: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn stairs_into_a_wall() {
+        let up = parse(
+            r#"Level(
+                textures: { "brick": "../brick_2.png" },
+                legend: { '.': Empty, 'w': Wall("brick"), 'U': StairsUp },
+                tiles: [".U."],
+                storeys: [(tiles: [".w."])],
+            )"#,
+        );
+        assert!(matches!(
+            up,
+            Err(LevelError::BlockedStairs {
+                storey: 0,
+                x: 1,
+                y: 0
+            })
+        ));
+        let down = parse(
+            r#"Level(
+                textures: { "brick": "../brick_2.png" },
+                legend: { '.': Empty, 'w': Wall("brick"), 'D': StairsDown },
+                tiles: ["w.."],
+                storeys: [(tiles: ["D.."])],
+            )"#,
+        );
+        assert!(matches!(
+            down,
+            Err(LevelError::BlockedStairs {
+                storey: 1,
+                x: 0,
+                y: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn bad_view_distance() {
+        for distance in ["0.0", "-1.0", "inf", "NaN"] {
+            let source = format!(
+                "Level(legend: {{ '.': Empty }}, tiles: [\".\"], view_distance: {})",
+                distance
+            );
+            assert!(
+                matches!(parse(&source), Err(LevelError::ViewDistance(_))),
+                "{}",
+                distance
+            );
+        }
+    }
+
+    #[test]
+    fn no_tiles() {
+        let result = parse("Level(legend: {}, tiles: [])");
+        assert!(matches!(result, Err(LevelError::Empty)));
+    }
+}
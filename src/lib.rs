@@ -0,0 +1,46 @@
+#![deny(clippy::all)]
+#![forbid(unsafe_code)]
+
+//! A basic grid based raycaster.
+//!
+//! The engine renders into any RGBA8 frame buffer laid out row by row, so it
+//! can be driven by a window (see `main.rs`) or used headlessly through
+//! [`Framebuffer`].
+
+use crate::drawing::{clear, line};
+use crate::drawing::{rect_filled, Point};
+
+pub mod collision;
+pub mod door;
+pub mod drawing;
+pub mod fog;
+pub mod framebuffer;
+pub mod grid;
+pub mod level;
+pub mod light;
+pub mod player;
+pub mod portal;
+pub mod ray;
+pub mod sprite;
+pub mod texture;
+pub mod viewport;
+pub mod world;
+
+pub use crate::door::{Door, DOOR};
+pub use crate::fog::{Fog, FogMode};
+pub use crate::framebuffer::Framebuffer;
+pub use crate::grid::{Grid, Segment, TileKind};
+pub use crate::level::{Level, LevelError, Spawn};
+pub use crate::light::Light;
+pub use crate::player::Player;
+pub use crate::portal::Portal;
+pub use crate::ray::{cast_ray, Hit, HitSide, Leg, RayHits};
+pub use crate::sprite::Sprite;
+pub use crate::texture::{Sampling, Texture, TextureTable};
+pub use crate::viewport::{Column, Face, Viewport, WallSlice};
+pub use crate::world::World;
+
+/// Default width of the frame buffer in pixels.
+pub const WIDTH: i32 = 1280;
+/// Default height of the frame buffer in pixels.
+pub const HEIGHT: i32 = 450;
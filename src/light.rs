@@ -0,0 +1,68 @@
+use crate::grid::Grid;
+use crate::ray::RayHits;
+use serde::Deserialize;
+use vecmath::vec2_len;
+
+/// A point light, baked into the grid's light map when the level loads.
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct Light {
+    pub x: f32,
+    pub y: f32,
+    /// How far the light reaches, in tiles.
+    pub radius: f32,
+    /// Brightness added right next to the light, fading to nothing at
+    /// `radius`.
+    #[serde(default = "default_intensity")]
+    pub intensity: f32,
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+impl Light {
+    pub fn new(x: f32, y: f32, radius: f32, intensity: f32) -> Light {
+        Light {
+            x,
+            y,
+            radius,
+            intensity,
+        }
+    }
+
+    /// How much this light brightens the middle of the tile at `x`, `y`.
+    /// Walls between the light and the tile cast shadows.
+    pub fn contribution(&self, grid: &Grid, x: i32, y: i32) -> f32 {
+        let dx = x as f32 + 0.5 - self.x;
+        let dy = y as f32 + 0.5 - self.y;
+        let distance = f32::hypot(dx, dy);
+        if distance >= self.radius {
+            return 0.0;
+        }
+        if distance > f32::EPSILON {
+            // Rays point up the screen but the grid runs down it
+            let direction = [dx / distance, -dy / distance];
+            // Doors sit across the middle of their tile, so anything hit
+            // right at the end of the ray is the tile itself. Light shines
+            // through see-through walls.
+            let blocked = RayHits::new([self.x, self.y], direction, grid, distance)
+                .filter(|hit| !grid.is_transparent(hit.tile))
+                .any(|hit| vec2_len(hit.offset) < distance - 0.01);
+            if blocked {
+                return 0.0;
+            }
+        }
+        let falloff = 1.0 - distance / self.radius;
+        self.intensity * falloff * falloff
+    }
+}
+
+/// Scale the brightness of a colour by a `light` level from 0 to 1, keeping
+/// its alpha.
+pub fn shade(colour: [u8; 4], light: f32) -> [u8; 4] {
+    let mut c = colour;
+    c[0..3].iter_mut().for_each(|c| {
+        *c = (*c as f32 * light) as u8;
+    });
+    c
+}
@@ -6,35 +6,134 @@ use pixels::wgpu::Color;
 use pixels::{Error, Pixels, SurfaceTexture};
 use std::time::Instant;
 use winit::dpi::LogicalSize;
-use winit::event::{Event, VirtualKeyCode};
+use winit::event::{DeviceEvent, Event, VirtualKeyCode};
 use winit::event_loop::{ControlFlow, EventLoop};
-use winit::window::WindowBuilder;
+use winit::window::{Window, WindowBuilder};
 use winit_input_helper::WinitInputHelper;
 
-use crate::drawing::{clear, line};
-use crate::drawing::{rect_filled, Point};
-use crate::world::World;
+use raycast::world::DEFAULT_LEVEL;
+use raycast::{Framebuffer, Sampling, World, HEIGHT, WIDTH};
 
-mod drawing;
-mod grid;
-mod player;
-mod ray;
-mod viewport;
-mod world;
+/// Smallest frame the minimap and viewport layout still makes sense for.
+const MIN_WIDTH: i32 = 320;
+const MIN_HEIGHT: i32 = 120;
+/// Largest frame, which is as big as textures get on most GPUs.
+const MAX_WIDTH: i32 = 8192;
+const MAX_HEIGHT: i32 = 8192;
 
-const WIDTH: i32 = 1280;
-const HEIGHT: i32 = 450;
+/// Radians turned per unit of mouse movement.
+const MOUSE_SENSITIVITY: f32 = 0.003;
+
+/// Command line usage, shown for arguments that can't be parsed.
+const USAGE: &str = "usage: raycast [--level <path>] [--size <width>x<height>] \
+[--sensitivity <radians>] [--bilinear] [--mipmaps] [--pose <x> <y> <angle>] \
+[--headless <output.png>]";
+
+/// Command line options, as given in [`USAGE`].
+struct Options {
+    level: String,
+    width: i32,
+    height: i32,
+    sensitivity: f32,
+    sampling: Sampling,
+    headless: Option<String>,
+    /// Where the player starts instead of the level's spawn point, as
+    /// `x y angle` in grid units and radians.
+    pose: Option<[f32; 3]>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options {
+            level: DEFAULT_LEVEL.to_string(),
+            width: WIDTH,
+            height: HEIGHT,
+            sensitivity: MOUSE_SENSITIVITY,
+            sampling: Sampling::default(),
+            headless: None,
+            pose: None,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--size" => {
+                    let size = args.next().ok_or("--size needs a value like 1280x450")?;
+                    let (width, height) = size
+                        .split_once('x')
+                        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+                        .ok_or_else(|| format!("invalid size `{}`, expected WIDTHxHEIGHT", size))?;
+                    options.width = width;
+                    options.height = height;
+                }
+                "--sensitivity" => {
+                    let sensitivity = args.next().ok_or("--sensitivity needs a value")?;
+                    options.sensitivity = sensitivity
+                        .parse()
+                        .map_err(|_| format!("invalid sensitivity `{}`", sensitivity))?;
+                }
+                "--bilinear" => options.sampling.bilinear = true,
+                "--mipmaps" => options.sampling.mipmaps = true,
+                "--level" => {
+                    let level = args.next().ok_or("--level needs a path")?;
+                    options.level = level.clone();
+                }
+                "--headless" => {
+                    let output = args.next().ok_or("--headless needs an output path")?;
+                    options.headless = Some(output.clone());
+                }
+                "--pose" => {
+                    let mut pose = [0.0; 3];
+                    for value in &mut pose {
+                        let arg = args.next().ok_or("--pose needs `x y angle`")?;
+                        *value = arg
+                            .parse()
+                            .map_err(|_| format!("invalid pose value `{}`", arg))?;
+                    }
+                    options.pose = Some(pose);
+                }
+                arg => return Err(format!("unexpected argument `{}`\n{}", arg, USAGE)),
+            }
+        }
+
+        if options.width < MIN_WIDTH || options.height < MIN_HEIGHT {
+            return Err(format!(
+                "size must be at least {}x{}",
+                MIN_WIDTH, MIN_HEIGHT
+            ));
+        }
+        if options.width > MAX_WIDTH || options.height > MAX_HEIGHT {
+            return Err(format!("size must be at most {}x{}", MAX_WIDTH, MAX_HEIGHT));
+        }
+        Ok(options)
+    }
+}
 
 fn main() -> Result<(), Error> {
     env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(output) = &options.headless {
+        run_headless(&options, output);
+        return Ok(());
+    }
+
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
     let window = {
-        let size = LogicalSize::new(WIDTH as f64, HEIGHT as f64);
+        let size = LogicalSize::new(options.width as f64, options.height as f64);
         WindowBuilder::new()
             .with_title("RayCasting")
             .with_inner_size(size)
-            .with_min_inner_size(size)
+            .with_min_inner_size(LogicalSize::new(MIN_WIDTH as f64, MIN_HEIGHT as f64))
             .build(&event_loop)
             .unwrap()
     };
@@ -42,7 +141,7 @@ fn main() -> Result<(), Error> {
     let mut pixels = {
         let window_size = window.inner_size();
         let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
-        Pixels::new(WIDTH as u32, HEIGHT as u32, surface_texture)?
+        Pixels::new(options.width as u32, options.height as u32, surface_texture)?
     };
     pixels.set_clear_color(Color {
         r: 0.0,
@@ -51,12 +150,26 @@ fn main() -> Result<(), Error> {
         a: 1.0,
     });
 
-    let mut world = World::new();
-    world.init();
+    let mut world = load_world(&options);
+
+    let mut mouse_captured = capture_mouse(&window, true);
+    let mut mouse_motion = (0.0, 0.0);
 
     let mut current_frame_time = Instant::now();
 
     event_loop.run(move |event, _, control_flow| {
+        // Raw mouse movement, which keeps coming while the cursor is grabbed
+        if let Event::DeviceEvent {
+            event: DeviceEvent::MouseMotion { delta },
+            ..
+        } = event
+        {
+            if mouse_captured {
+                mouse_motion.0 += delta.0 as f32;
+                mouse_motion.1 += delta.1 as f32;
+            }
+        }
+
         // Handle input events
         if input.update(&event) {
             // Close events
@@ -65,9 +178,24 @@ fn main() -> Result<(), Error> {
                 return;
             }
 
-            // Resize the window
+            // Tab releases the mouse, or captures it again
+            if input.key_pressed(VirtualKeyCode::Tab) {
+                mouse_captured = capture_mouse(&window, !mouse_captured);
+            }
+            world.look(
+                mouse_motion.0 * options.sensitivity,
+                mouse_motion.1 * options.sensitivity,
+            );
+            mouse_motion = (0.0, 0.0);
+
+            // Resize the window, and the frame buffer to match its logical size
             if let Some(size) = input.window_resized() {
                 pixels.resize_surface(size.width, size.height);
+                let logical: LogicalSize<u32> = size.to_logical(window.scale_factor());
+                let width = (logical.width as i32).max(MIN_WIDTH);
+                let height = (logical.height as i32).max(MIN_HEIGHT);
+                pixels.resize_buffer(width as u32, height as u32);
+                world.resize(width, height);
             }
 
             // Update internal state and request a redraw
@@ -87,8 +215,48 @@ fn main() -> Result<(), Error> {
                 .is_err()
             {
                 *control_flow = ControlFlow::Exit;
-                return;
             }
         }
     });
 }
+
+/// Grab and hide the cursor so mouse movement turns the player, or release
+/// it. Returns whether the mouse ended up captured.
+fn capture_mouse(window: &Window, capture: bool) -> bool {
+    if let Err(e) = window.set_cursor_grab(capture) {
+        error!("failed to grab the cursor: {}", e);
+        window.set_cursor_visible(true);
+        return false;
+    }
+    window.set_cursor_visible(!capture);
+    capture
+}
+
+/// Create the world and load the level, exiting if the level can't be loaded.
+fn load_world(options: &Options) -> World {
+    let mut world = World::new(options.width, options.height);
+    if let Err(e) = world.load_level(&options.level) {
+        error!("failed to load level {}: {}", options.level, e);
+        std::process::exit(1);
+    }
+    world.set_sampling(options.sampling);
+    if let Some([x, y, angle]) = options.pose {
+        world.set_player_pose(x, y, angle);
+    }
+    world
+}
+
+/// Render a single frame without opening a window and write it out as a PNG.
+///
+/// The optional pose is in grid units and radians. Without a pose the player
+/// starts at the level's spawn point.
+fn run_headless(options: &Options, output: &str) {
+    let world = load_world(options);
+
+    let mut framebuffer = Framebuffer::new(options.width, options.height);
+    world.render(&mut framebuffer);
+    if let Err(e) = framebuffer.save_png(output) {
+        error!("failed to write {}: {}", output, e);
+        std::process::exit(1);
+    }
+}
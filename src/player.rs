@@ -1,50 +1,105 @@
+use crate::collision::{floor_and_ceiling, move_body};
+use crate::drawing::Frame;
 use crate::grid::Grid;
-use crate::ray::cast_ray;
-use crate::{line, rect_filled, Point, HEIGHT, WIDTH};
+use crate::viewport::{Column, Face};
+use crate::{line, rect_filled, Point};
 use std::f32::consts::{FRAC_PI_2, PI};
 use vecmath::{vec2_len, Vector2};
 use winit::event::VirtualKeyCode;
 use winit_input_helper::WinitInputHelper;
 
+/// How far the player can look up or down, in radians. The view is sheared
+/// rather than rotated, which looks increasingly wrong past this.
+pub const MAX_PITCH: f32 = 0.5;
+
+/// Height of the player's eyes above their feet, in wall heights.
+pub const EYE_HEIGHT: f32 = 0.5;
+/// Eye height while crouching.
+pub const CROUCH_HEIGHT: f32 = 0.25;
+/// How fast the eyes move between standing and crouching heights, in wall
+/// heights per second.
+const CROUCH_SPEED: f32 = 2.0;
+/// Downward acceleration in wall heights per second squared.
+const GRAVITY: f32 = 10.0;
+/// Upward speed at the start of a jump, reaching about 0.4 of a wall high.
+const JUMP_SPEED: f32 = 2.8;
+
 pub struct Player {
     pub x: f32,
     pub y: f32,
     pub angle: f32,
+    /// How far up (positive) or down the player is looking, in radians.
+    pub pitch: f32,
+    /// Size of the player for collisions with walls, in grid units.
+    pub radius: f32,
+    /// Height of the player's feet, in wall heights. They rest on the floor
+    /// unless jumping or falling.
+    pub elevation: f32,
+    /// Upward speed, in wall heights per second.
+    pub vertical_speed: f32,
+    /// How far the player's eyes are above their feet, in wall heights.
+    pub eye_height: f32,
+    /// Height of the lowest ceiling over the player, which their eyes are
+    /// kept below.
+    pub ceiling: f32,
 }
 
 impl Player {
     pub fn update(&mut self, input: &WinitInputHelper, grid: &Grid, delta: f32) {
+        // W/S move forward and back, A/D strafe left and right
+        let mut forward = 0.0;
+        let mut strafe = 0.0;
         if input.key_held(VirtualKeyCode::W) {
-            let x = self.x as usize;
-            let y = self.y as usize;
-            let projected_x = (self.x + self.angle.cos().signum() * 0.25) as usize;
-            let projected_y = (self.y + self.angle.sin().signum() * -0.25) as usize;
-            if grid.tiles[projected_x % grid.width as usize + y * grid.width as usize] == 0 {
-                self.x += self.angle.cos() * 2.0 * delta;
-            }
-            if grid.tiles[x % grid.width as usize + projected_y * grid.width as usize] == 0 {
-                self.y += self.angle.sin() * -2.0 * delta;
-            }
+            forward += 1.0;
         }
         if input.key_held(VirtualKeyCode::S) {
-            let x = self.x as usize;
-            let y = self.y as usize;
-            let projected_x = (self.x - self.angle.cos().signum() * 0.25) as usize;
-            let projected_y = (self.y - self.angle.sin().signum() * -0.25) as usize;
-            if grid.tiles[projected_x % grid.width as usize + y * grid.width as usize] == 0 {
-                self.x -= self.angle.cos() * 2.0 * delta;
-            }
-            if grid.tiles[x % grid.width as usize + projected_y * grid.width as usize] == 0 {
-                self.y -= self.angle.sin() * -2.0 * delta;
-            }
+            forward -= 1.0;
+        }
+        if input.key_held(VirtualKeyCode::D) {
+            strafe += 1.0;
         }
         if input.key_held(VirtualKeyCode::A) {
-            self.angle += 1.5 * delta;
-            self.angle = self.angle % (2.0 * PI);
+            strafe -= 1.0;
         }
-        if input.key_held(VirtualKeyCode::D) {
-            self.angle -= 1.5 * delta;
-            self.angle = (self.angle % (2.0 * PI) + (2.0 * PI)) % (2.0 * PI);
+        // C or Ctrl to crouch, which also slows the player down
+        let crouching =
+            input.key_held(VirtualKeyCode::C) || input.key_held(VirtualKeyCode::LControl);
+        if forward != 0.0 || strafe != 0.0 {
+            // keep diagonal movement the same speed as straight movement
+            let walk_speed = if crouching { 1.0 } else { 2.0 };
+            let speed = walk_speed * delta / f32::hypot(forward, strafe);
+            let (sin, cos) = self.angle.sin_cos();
+            self.step(
+                grid,
+                (cos * forward + sin * strafe) * speed,
+                (-sin * forward + cos * strafe) * speed,
+            );
+        }
+
+        let (floor, ceiling) = floor_and_ceiling(grid, [self.x, self.y], self.radius);
+        self.ceiling = ceiling;
+
+        // Space to jump, but only from the floor
+        if input.key_pressed(VirtualKeyCode::Space) && self.elevation <= floor {
+            self.vertical_speed = JUMP_SPEED;
+        }
+        self.fall(floor, delta);
+        // Stay crouched under ceilings too low to stand up under
+        let eye_height = if crouching { CROUCH_HEIGHT } else { EYE_HEIGHT };
+        let eye_height = eye_height.min(ceiling - self.elevation - 0.05);
+        self.crouch_towards(eye_height, delta);
+
+        if input.key_held(VirtualKeyCode::Left) {
+            self.turn(1.5 * delta);
+        }
+        if input.key_held(VirtualKeyCode::Right) {
+            self.turn(-1.5 * delta);
+        }
+        if input.key_held(VirtualKeyCode::Up) {
+            self.tilt(delta);
+        }
+        if input.key_held(VirtualKeyCode::Down) {
+            self.tilt(-delta);
         }
         if input.key_held(VirtualKeyCode::Key1) {
             self.angle = 0.0;
@@ -58,12 +113,90 @@ impl Player {
         if input.key_held(VirtualKeyCode::Key4) {
             self.angle = FRAC_PI_2 * 3.0;
         }
+    }
+
+    /// Turn anticlockwise by `angle` radians.
+    pub fn turn(&mut self, angle: f32) {
+        self.angle = (self.angle + angle).rem_euclid(2.0 * PI);
+    }
+
+    /// Move up or down under gravity, landing on a floor at height `floor`.
+    /// Steps up are climbed straight away.
+    fn fall(&mut self, floor: f32, delta: f32) {
+        if self.elevation <= floor && self.vertical_speed <= 0.0 {
+            self.elevation = floor;
+            return;
+        }
+        self.vertical_speed -= GRAVITY * delta;
+        self.elevation += self.vertical_speed * delta;
+        if self.elevation <= floor {
+            self.elevation = floor;
+            self.vertical_speed = 0.0;
+        }
+    }
 
-        self.x = self.x.clamp(0.0, (grid.tile_size * grid.width) as f32);
-        self.y = self.y.clamp(0.0, (grid.tile_size * grid.width) as f32);
+    /// Move the eyes towards `eye_height` above the feet at [`CROUCH_SPEED`].
+    fn crouch_towards(&mut self, eye_height: f32, delta: f32) {
+        let step = CROUCH_SPEED * delta;
+        self.eye_height += (eye_height - self.eye_height).clamp(-step, step);
     }
 
-    pub fn draw(&self, frame: &mut [u8], grid: &Grid) {
+    /// Height of the player's eyes, in wall heights. Kept a little below the
+    /// ceiling so it never gets drawn edge on.
+    pub fn eye_z(&self) -> f32 {
+        (self.elevation + self.eye_height).min(self.ceiling - 0.05)
+    }
+
+    /// Look up by `angle` radians, or down if negative, without tipping
+    /// further than [`MAX_PITCH`].
+    pub fn tilt(&mut self, angle: f32) {
+        self.pitch = (self.pitch + angle).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    /// Move by `dx`, `dy` in grid units, sliding along any walls in the way
+    /// and stopping at steps too high to climb.
+    fn step(&mut self, grid: &Grid, dx: f32, dy: f32) {
+        let position = move_body(
+            grid,
+            [self.x, self.y],
+            [dx, dy],
+            self.radius,
+            self.elevation,
+            self.eye_height,
+        );
+        let from = [self.x, self.y];
+        self.x = position[0];
+        self.y = position[1];
+        self.go_through_portal(grid, from);
+    }
+
+    /// Come out of the linked portal if the player has walked into one
+    /// through its face from `from`, turned to face the same way relative to
+    /// it as they went in.
+    fn go_through_portal(&mut self, grid: &Grid, from: Vector2<f32>) {
+        let portal = match grid.portal_at(self.x.floor() as i32, self.y.floor() as i32) {
+            Some(portal) if portal.is_in_front(from) => portal,
+            _ => return,
+        };
+        if let Some(exit) = grid.portal_exit(portal) {
+            let (position, _) = portal.transform(exit, [self.x, self.y], [0.0, 0.0]);
+            self.x = position[0];
+            self.y = position[1];
+            self.turn(portal.rotation(exit));
+        }
+    }
+
+    /// The tile directly in front of the player, within arm's reach.
+    pub fn facing_tile(&self) -> (i32, i32) {
+        (
+            (self.x + self.angle.cos()).floor() as i32,
+            (self.y - self.angle.sin()).floor() as i32,
+        )
+    }
+
+    /// Draw the player on the minimap, with the rays cast for each column
+    /// running out to what they hit, or to `view_distance` if they missed.
+    pub fn draw(&self, frame: &mut Frame, grid: &Grid, columns: &[Column], view_distance: f32) {
         let player_colour = [255, 0, 0, 255];
         let screen_x = (self.x * grid.tile_size as f32) as i32;
         let screen_y = (self.y * grid.tile_size as f32) as i32;
@@ -93,18 +226,9 @@ impl Player {
             [255, 255, 0, 255],
         );
 
-        // Screen width, should be from constant or at least defined in one place...
-        let width = WIDTH - 10 - (HEIGHT + 20);
-        // To avoid distortion we can't send rays out with equal angles, this bunches
-        // them up in the centre. Instead we use divide up the "opposite" side of the
-        // triangle into equal portions and figure out the angles using tan θ = op/adj
-        let fov = 2.0 * (0.66 / 1.0 as f32).atan();
-        let increment = (1.0 * (fov).sin()) / (width as f32 / 2.0);
-        for x in 0..width {
-            let angle = -(increment * (x - width / 2) as f32).atan() + self.angle;
-
-            let direction = [angle.cos(), angle.sin()];
-            self.draw_fov(screen_x, screen_y, grid, frame, direction);
+        // One ray per column of the 3D viewport, as cast for the view.
+        for column in columns {
+            self.draw_fov(screen_x, screen_y, grid, frame, column, view_distance);
         }
     }
 
@@ -113,24 +237,28 @@ impl Player {
         screen_x: i32,
         screen_y: i32,
         grid: &Grid,
-        frame: &mut [u8],
-        direction: Vector2<f32>,
+        frame: &mut Frame,
+        column: &Column,
+        view_distance: f32,
     ) -> f32 {
-        let origin: Vector2<f32> = [self.x, self.y];
-        let hit = cast_ray(origin, direction, grid);
-        let mut length = 0.0;
-        let cast_point = match hit {
-            None => Point {
-                x: (screen_x as f32 + direction[0] * 10.0 * grid.tile_size as f32) as i32,
-                y: (screen_y as f32 + direction[1] * -10.0 * grid.tile_size as f32) as i32,
-            },
-            Some((h, _s)) => {
-                length = vec2_len(h);
-                Point {
-                    x: (screen_x as f32 + h[0] * grid.tile_size as f32) as i32,
-                    y: (screen_y as f32 - h[1] * grid.tile_size as f32) as i32,
-                }
-            }
+        let direction = column.direction;
+        // Show the ray as far as the first wall that can't be seen through,
+        // or the first mirror or portal it bounced off or went through
+        let solid = column
+            .slices
+            .iter()
+            .find(|slice| !slice.transparent && slice.leg == 0 && slice.face == Face::Wall)
+            .map(|slice| vec2_len(slice.hit.offset));
+        let bounce = column.legs.get(1).map(|leg| leg.travelled);
+        // nor past the far side of the grid, however far the view reaches
+        let across = f32::hypot(grid.width as f32, grid.height as f32);
+        let length = solid
+            .into_iter()
+            .chain(bounce)
+            .fold(view_distance.min(across), f32::min);
+        let cast_point = Point {
+            x: (screen_x as f32 + direction[0] * length * grid.tile_size as f32) as i32,
+            y: (screen_y as f32 - direction[1] * length * grid.tile_size as f32) as i32,
         };
 
         line(
@@ -156,6 +284,39 @@ impl Player {
             [0, 0, 255, 255],
         );
 
-        return length;
+        length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::TileKind;
+    use crate::portal::Portal;
+
+    #[test]
+    fn walks_through_the_face_of_a_portal() {
+        // a portal facing east at 3, 2 leading out of one facing north at 6, 2
+        let mut grid = Grid::new(10, 5);
+        grid.kinds[1] = TileKind::Portal;
+        for (tile, facing, link) in [([3, 2], [1, 0], [6, 2]), ([6, 2], [0, -1], [3, 2])] {
+            let i = (tile[0] + tile[1] * grid.width) as usize;
+            grid.tiles[i] = 1;
+            grid.portals.insert(i, Portal { tile, facing, link });
+        }
+        let mut player = Player {
+            x: 4.5,
+            y: 2.5,
+            angle: PI,
+            pitch: 0.0,
+            radius: 0.25,
+            elevation: 0.0,
+            vertical_speed: 0.0,
+            eye_height: EYE_HEIGHT,
+            ceiling: 1.0,
+        };
+        player.step(&grid, -1.0, 0.0);
+        assert!((player.x - 6.5).abs() < 0.01 && (player.y - 1.5).abs() < 0.01);
+        assert!((player.angle - FRAC_PI_2).abs() < 0.01);
     }
 }
@@ -0,0 +1,211 @@
+use vecmath::{vec2_dot, Vector2};
+
+/// One end of a pair of portals. The inside of a portal's tile is joined to
+/// the tile in front of its partner's face, so anything going into one comes
+/// out of the other, turned to face out of it. Only the face leads anywhere;
+/// the tile's other sides are wall.
+///
+/// Points and directions are in grid space, with y pointing down.
+#[derive(Copy, Clone, Debug)]
+pub struct Portal {
+    /// The portal's tile.
+    pub tile: [i32; 2],
+    /// Unit vector out of the portal's face, along one of the axes.
+    pub facing: [i32; 2],
+    /// The tile of the portal this one leads to.
+    pub link: [i32; 2],
+}
+
+impl Portal {
+    /// Where `point` ends up, and which way `direction` points, after going
+    /// into this portal and coming out of `exit`.
+    pub fn transform(
+        &self,
+        exit: &Portal,
+        point: Vector2<f32>,
+        direction: Vector2<f32>,
+    ) -> (Vector2<f32>, Vector2<f32>) {
+        let (normal, across) = self.axes();
+        let (exit_normal, exit_across) = exit.axes();
+        let exit_centre = exit.face_centre();
+
+        // Going in through one face is coming out of the other, so both axes
+        // flip as well as turning to line up with the exit.
+        let relative = self.relative(point);
+        let (out, along) = (vec2_dot(relative, normal), vec2_dot(relative, across));
+        let point = [
+            exit_centre[0] - out * exit_normal[0] - along * exit_across[0],
+            exit_centre[1] - out * exit_normal[1] - along * exit_across[1],
+        ];
+        let (out, along) = (vec2_dot(direction, normal), vec2_dot(direction, across));
+        let direction = [
+            -out * exit_normal[0] - along * exit_across[0],
+            -out * exit_normal[1] - along * exit_across[1],
+        ];
+        (point, direction)
+    }
+
+    /// How far anticlockwise something is turned by going into this portal
+    /// and coming out of `exit`, in radians.
+    pub fn rotation(&self, exit: &Portal) -> f32 {
+        let (_, [x, y]) = self.transform(exit, [0.0, 0.0], [1.0, 0.0]);
+        // y is flipped, as angles are measured with y pointing up
+        (-y).atan2(x)
+    }
+
+    /// Whether a ray that hit the portal's tile where the surface points
+    /// along `normal` hit the portal's face, rather than one of its other
+    /// sides.
+    pub fn is_face(&self, normal: Vector2<f32>) -> bool {
+        let (facing, _) = self.axes();
+        vec2_dot(normal, facing) > 0.5
+    }
+
+    /// Whether `point` is in front of the portal's face.
+    pub fn is_in_front(&self, point: Vector2<f32>) -> bool {
+        let (normal, _) = self.axes();
+        vec2_dot(self.relative(point), normal) >= 0.0
+    }
+
+    /// Whether something centred on `point` is on its way into the portal:
+    /// lined up with its face and not behind its tile. Anything else
+    /// touching the tile is bumping into one of its other sides.
+    pub fn lets_in(&self, point: Vector2<f32>) -> bool {
+        let (normal, across) = self.axes();
+        let relative = self.relative(point);
+        vec2_dot(relative, across).abs() <= 0.5 && vec2_dot(relative, normal) >= -1.0
+    }
+
+    /// `point` relative to the middle of the portal's face.
+    fn relative(&self, point: Vector2<f32>) -> Vector2<f32> {
+        let centre = self.face_centre();
+        [point[0] - centre[0], point[1] - centre[1]]
+    }
+
+    /// The portal's facing and the direction a quarter turn from it.
+    fn axes(&self) -> (Vector2<f32>, Vector2<f32>) {
+        let [x, y] = self.facing;
+        ([x as f32, y as f32], [-y as f32, x as f32])
+    }
+
+    /// The middle of the portal's face.
+    fn face_centre(&self) -> Vector2<f32> {
+        [
+            self.tile[0] as f32 + 0.5 + self.facing[0] as f32 * 0.5,
+            self.tile[1] as f32 + 0.5 + self.facing[1] as f32 * 0.5,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::{PI, TAU};
+
+    const FACINGS: [[i32; 2]; 4] = [[1, 0], [0, -1], [-1, 0], [0, 1]];
+
+    fn portal(tile: [i32; 2], facing: [i32; 2]) -> Portal {
+        Portal {
+            tile,
+            facing,
+            link: [0, 0],
+        }
+    }
+
+    fn assert_near(actual: Vector2<f32>, expected: Vector2<f32>) {
+        assert!(
+            (actual[0] - expected[0]).abs() < 1e-5 && (actual[1] - expected[1]).abs() < 1e-5,
+            "{:?} isn't {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn transform_carries_through_every_pair_of_facings() {
+        for facing in FACINGS {
+            for exit_facing in FACINGS {
+                let entry = portal([2, 3], facing);
+                let exit = portal([7, 5], exit_facing);
+                let [x, y] = facing.map(|v| v as f32);
+                let [exit_x, exit_y] = exit_facing.map(|v| v as f32);
+                let centre = entry.face_centre();
+                let exit_centre = exit.face_centre();
+
+                // Going straight in comes straight out
+                let (point, direction) = entry.transform(&exit, centre, [-x, -y]);
+                assert_near(point, exit_centre);
+                assert_near(direction, [exit_x, exit_y]);
+
+                // A little way in is the same way out of the exit
+                let inside = [centre[0] - x * 0.25, centre[1] - y * 0.25];
+                let (point, _) = entry.transform(&exit, inside, [0.0, 0.0]);
+                assert_near(
+                    point,
+                    [
+                        exit_centre[0] + exit_x * 0.25,
+                        exit_centre[1] + exit_y * 0.25,
+                    ],
+                );
+
+                // and something on the right going in is on the right coming
+                // out, rather than mirrored. Right of `(x, y)` is `(-y, x)`
+                // with y pointing down.
+                let right = [centre[0] + y * 0.25, centre[1] - x * 0.25];
+                let (point, _) = entry.transform(&exit, right, [0.0, 0.0]);
+                assert_near(
+                    point,
+                    [
+                        exit_centre[0] - exit_y * 0.25,
+                        exit_centre[1] + exit_x * 0.25,
+                    ],
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rotation_turns_from_going_in_to_coming_out() {
+        // Anticlockwise angle of a direction, with y pointing up
+        let angle = |[x, y]: [i32; 2]| (-y as f32).atan2(x as f32);
+        for facing in FACINGS {
+            for exit_facing in FACINGS {
+                let entry = portal([2, 3], facing);
+                let exit = portal([7, 5], exit_facing);
+                let expected = angle(exit_facing) - angle([-facing[0], -facing[1]]);
+                let difference = (entry.rotation(&exit) - expected).rem_euclid(TAU);
+                assert!(
+                    difference < 1e-5 || TAU - difference < 1e-5,
+                    "{:?} to {:?} turned {} rather than {}",
+                    facing,
+                    exit_facing,
+                    entry.rotation(&exit),
+                    expected
+                );
+            }
+        }
+        // Portals facing each other's way keep the direction, and facing the
+        // same way turn it right round
+        let east = portal([2, 3], [1, 0]);
+        let west = portal([7, 3], [-1, 0]);
+        assert!(east.rotation(&west).abs() < 1e-5);
+        assert!((east.rotation(&east).abs() - PI).abs() < 1e-5);
+    }
+
+    #[test]
+    fn only_the_face_leads_anywhere() {
+        let portal = portal([2, 3], [1, 0]);
+        assert!(portal.is_face([1.0, 0.0]));
+        assert!(!portal.is_face([-1.0, 0.0]));
+        assert!(!portal.is_face([0.0, 1.0]));
+
+        assert!(portal.lets_in([3.2, 3.5]));
+        assert!(portal.lets_in([2.5, 3.5]));
+        assert!(!portal.lets_in([1.8, 3.5]));
+        assert!(!portal.lets_in([2.5, 2.8]));
+        assert!(!portal.lets_in([2.5, 4.2]));
+
+        assert!(portal.is_in_front([3.2, 3.5]));
+        assert!(!portal.is_in_front([2.9, 3.5]));
+    }
+}
@@ -1,5 +1,7 @@
-use crate::grid::Grid;
-use vecmath::{vec2_scale, Vector2};
+use crate::door::Door;
+use crate::grid::{Grid, Segment};
+use crate::portal::Portal;
+use vecmath::{vec2_len, vec2_scale, Vector2};
 
 #[derive(Copy, Clone)]
 pub enum HitSide {
@@ -7,70 +9,526 @@ pub enum HitSide {
     Y,
 }
 
+/// Where a ray hit a wall.
+#[derive(Copy, Clone)]
+pub struct Hit {
+    /// Vector from the ray origin to the hit point.
+    pub offset: Vector2<f32>,
+    pub side: HitSide,
+    /// Value of the tile that was hit.
+    pub tile: u8,
+    /// How far along the wall the hit is, for sampling the texture.
+    pub u: f32,
+    /// Unit vector out of the face that was hit, towards the ray's origin,
+    /// with y pointing down the grid.
+    pub normal: Vector2<f32>,
+}
+
+/// Cast a ray from `origin` along `direction` (with y pointing up) through the
+/// grid, returning the first wall it hits within `max_distance` tiles.
 pub fn cast_ray(
     origin: Vector2<f32>,
     direction: Vector2<f32>,
     grid: &Grid,
-) -> Option<(Vector2<f32>, HitSide)> {
-    let mut ray_unit_step_size: Vector2<f32> = [
-        (1.0 + (direction[1] / direction[0]) * (direction[1] / direction[0])).sqrt(),
-        (1.0 + (direction[0] / direction[1]) * (direction[0] / direction[1])).sqrt(),
-    ];
-    if ray_unit_step_size[0].is_infinite() {
-        ray_unit_step_size[0] = f32::MAX;
+    max_distance: f32,
+) -> Option<Hit> {
+    RayHits::new(origin, direction, grid, max_distance).next()
+}
+
+/// One straight stretch of a ray. Rays start a new leg each time they bounce
+/// off a mirror or go through a portal.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Leg {
+    /// Where the leg starts, in grid coordinates.
+    pub origin: Vector2<f32>,
+    /// Unit direction of the leg, with y pointing up.
+    pub direction: Vector2<f32>,
+    /// How far the ray travelled before this leg started.
+    pub travelled: f32,
+}
+
+impl Leg {
+    /// The point in grid coordinates `offset` from the start of the leg,
+    /// where `offset` has y pointing up like a hit's offset.
+    pub fn point(&self, offset: Vector2<f32>) -> Vector2<f32> {
+        [self.origin[0] + offset[0], self.origin[1] - offset[1]]
     }
-    if ray_unit_step_size[1].is_infinite() {
-        ray_unit_step_size[1] = f32::MAX;
+}
+
+/// Every wall a ray passes through within `max_distance` tiles, nearest
+/// first. Walls don't stop the ray, so callers decide when to stop looking.
+///
+/// Mirrors and portals are hit like any other wall, but if the ray has
+/// bounces left it then carries on reflected off the mirror or out of the
+/// linked portal, starting a new [`Leg`]. Hits are relative to the leg they
+/// are on.
+pub struct RayHits<'a> {
+    leg: Leg,
+    grid: &'a Grid,
+    max_distance: f32,
+    /// How many more times the ray may bounce off mirrors or go through
+    /// portals.
+    bounces: u32,
+    /// Whether steps in the floor or ceiling are hit as well as walls.
+    steps: bool,
+    ray_unit_step_size: Vector2<f32>,
+    current_tile: Vector2<i32>,
+    ray_length_1d: Vector2<f32>,
+    step: Vector2<i32>,
+    /// Whether the tile the ray starts in has been checked yet. Only thin
+    /// walls can be hit from inside their own tile.
+    started: bool,
+}
+
+impl<'a> RayHits<'a> {
+    pub fn new(
+        origin: Vector2<f32>,
+        direction: Vector2<f32>,
+        grid: &'a Grid,
+        max_distance: f32,
+    ) -> RayHits<'a> {
+        let mut hits = RayHits {
+            leg: Leg {
+                origin,
+                direction,
+                travelled: 0.0,
+            },
+            grid,
+            max_distance,
+            bounces: 0,
+            steps: false,
+            ray_unit_step_size: [0.0, 0.0],
+            current_tile: [0, 0],
+            ray_length_1d: [0.0, 0.0],
+            step: [0, 0],
+            started: false,
+        };
+        hits.start_leg();
+        hits
     }
-    let mut current_tile: Vector2<i32> = [origin[0] as i32, origin[1] as i32];
-    let mut ray_length_1d: Vector2<f32> = [0.0, 0.0];
-    let step: Vector2<i32> = [direction[0].signum() as i32, direction[1].signum() as i32];
 
-    if direction[0] < 0.0 {
-        ray_length_1d[0] = (origin[0] - current_tile[0] as f32) * ray_unit_step_size[0];
-    } else {
-        ray_length_1d[0] = (current_tile[0] as f32 + 1.0 - origin[0]) * ray_unit_step_size[0];
+    /// Let the ray bounce off or go through up to `bounces` mirrors and
+    /// portals. Without any bounces they stop rays like walls.
+    pub fn with_bounces(mut self, bounces: u32) -> RayHits<'a> {
+        self.bounces = bounces;
+        self
     }
-    if direction[1] < 0.0 {
-        ray_length_1d[1] = (current_tile[1] as f32 + 1.0 - origin[1]) * ray_unit_step_size[1];
-    } else {
-        ray_length_1d[1] = (origin[1] - current_tile[1] as f32) * ray_unit_step_size[1];
-    }
-
-    let max_distance = 10.0;
-    let mut distance = 0.0;
-    let mut tile_found = false;
-    let mut side = HitSide::X;
-    while !tile_found && distance < max_distance {
-        if ray_length_1d[0] < ray_length_1d[1] {
-            current_tile[0] += step[0];
-            distance = ray_length_1d[0];
-            ray_length_1d[0] += ray_unit_step_size[0];
-            side = HitSide::Y;
+
+    /// Also hit the faces of steps, where the floor or ceiling changes height
+    /// from one open tile to the next. The hit's tile is the one being
+    /// stepped into.
+    pub fn with_steps(mut self) -> RayHits<'a> {
+        self.steps = true;
+        self
+    }
+
+    /// The leg of the ray being cast. Checking it after each hit shows when
+    /// the ray has bounced.
+    pub fn leg(&self) -> Leg {
+        self.leg
+    }
+
+    /// Set up the DDA to walk the grid from the start of the current leg.
+    fn start_leg(&mut self) {
+        let Leg {
+            origin, direction, ..
+        } = self.leg;
+        let mut ray_unit_step_size: Vector2<f32> = [
+            (1.0 + (direction[1] / direction[0]) * (direction[1] / direction[0])).sqrt(),
+            (1.0 + (direction[0] / direction[1]) * (direction[0] / direction[1])).sqrt(),
+        ];
+        if ray_unit_step_size[0].is_infinite() {
+            ray_unit_step_size[0] = f32::MAX;
+        }
+        if ray_unit_step_size[1].is_infinite() {
+            ray_unit_step_size[1] = f32::MAX;
+        }
+        let current_tile: Vector2<i32> = [origin[0] as i32, origin[1] as i32];
+        let mut ray_length_1d: Vector2<f32> = [0.0, 0.0];
+        let step: Vector2<i32> = [direction[0].signum() as i32, direction[1].signum() as i32];
+
+        if direction[0] < 0.0 {
+            ray_length_1d[0] = (origin[0] - current_tile[0] as f32) * ray_unit_step_size[0];
+        } else {
+            ray_length_1d[0] = (current_tile[0] as f32 + 1.0 - origin[0]) * ray_unit_step_size[0];
+        }
+        if direction[1] < 0.0 {
+            ray_length_1d[1] = (current_tile[1] as f32 + 1.0 - origin[1]) * ray_unit_step_size[1];
         } else {
-            current_tile[1] -= step[1];
-            distance = ray_length_1d[1];
-            ray_length_1d[1] += ray_unit_step_size[1];
-            side = HitSide::X;
+            ray_length_1d[1] = (origin[1] - current_tile[1] as f32) * ray_unit_step_size[1];
         }
 
-        if distance > max_distance {
-            return None;
+        self.ray_unit_step_size = ray_unit_step_size;
+        self.current_tile = current_tile;
+        self.ray_length_1d = ray_length_1d;
+        self.step = step;
+        self.started = false;
+    }
+
+    /// Carry on from `hit` on a mirror, reflected about its normal.
+    fn bounce(&mut self, hit: &Hit) {
+        // Reflect in grid space, where the normal is
+        let [x, y] = [self.leg.direction[0], -self.leg.direction[1]];
+        let n = hit.normal;
+        let dot = x * n[0] + y * n[1];
+        let reflected = [x - 2.0 * dot * n[0], y - 2.0 * dot * n[1]];
+        // Start just off the mirror so the ray doesn't hit it again
+        let point = self.leg.point(hit.offset);
+        self.leg = Leg {
+            origin: [point[0] + n[0] * 0.001, point[1] + n[1] * 0.001],
+            direction: [reflected[0], -reflected[1]],
+            travelled: self.leg.travelled + vec2_len(hit.offset),
+        };
+        self.bounces -= 1;
+        self.start_leg();
+    }
+
+    /// Carry on out of the portal linked to `portal` from where `hit` went
+    /// into it. Portals that don't lead anywhere stop the ray like walls.
+    fn teleport(&mut self, portal: &Portal, hit: &Hit) {
+        let exit = match self.grid.portal_exit(portal) {
+            Some(exit) => exit,
+            None => return,
+        };
+        let [x, y] = self.leg.direction;
+        let (point, [x, y]) = portal.transform(exit, self.leg.point(hit.offset), [x, -y]);
+        // Start just inside the tile in front of the exit
+        self.leg = Leg {
+            origin: [point[0] + x * 0.001, point[1] + y * 0.001],
+            direction: [x, -y],
+            travelled: self.leg.travelled + vec2_len(hit.offset),
+        };
+        self.bounces -= 1;
+        self.start_leg();
+    }
+}
+
+impl<'a> Iterator for RayHits<'a> {
+    type Item = Hit;
+
+    fn next(&mut self) -> Option<Hit> {
+        let hit = self.next_on_leg()?;
+        let grid = self.grid;
+        if self.bounces > 0 {
+            let [x, y] = self.current_tile;
+            if let Some(portal) = grid.portal_at(x, y) {
+                // the back and sides of a portal are just wall
+                if portal.is_face(hit.normal) {
+                    self.teleport(portal, &hit);
+                }
+            } else if grid.is_mirror(hit.tile) {
+                self.bounce(&hit);
+            }
+        }
+        Some(hit)
+    }
+}
+
+impl<'a> RayHits<'a> {
+    /// The next wall hit along the current leg.
+    fn next_on_leg(&mut self) -> Option<Hit> {
+        let grid = self.grid;
+        let Leg {
+            origin,
+            direction,
+            travelled,
+        } = self.leg;
+        if !self.started {
+            self.started = true;
+            let [x, y] = self.current_tile;
+            if let Some(segment) = grid.segment_at(x, y) {
+                let tile = grid.tile_at(x, y);
+                let hit = hit_segment(origin, direction, self.current_tile, segment, tile);
+                if hit.is_some() {
+                    return hit;
+                }
+            }
         }
-        if current_tile[0] >= 0
-            && current_tile[0] < grid.width
-            && current_tile[1] >= 0
-            && current_tile[1] < grid.height
-        {
-            if grid.tiles[(current_tile[0] + current_tile[1] * grid.width) as usize] > 0 {
-                tile_found = true;
+        loop {
+            let previous = self.current_tile;
+            let distance;
+            let side;
+            if self.ray_length_1d[0] < self.ray_length_1d[1] {
+                self.current_tile[0] += self.step[0];
+                distance = self.ray_length_1d[0];
+                self.ray_length_1d[0] += self.ray_unit_step_size[0];
+                side = HitSide::Y;
+            } else {
+                self.current_tile[1] -= self.step[1];
+                distance = self.ray_length_1d[1];
+                self.ray_length_1d[1] += self.ray_unit_step_size[1];
+                side = HitSide::X;
+            }
+
+            if travelled + distance > self.max_distance {
+                return None;
+            }
+            let [x, y] = self.current_tile;
+            if x < 0 || x >= grid.width || y < 0 || y >= grid.height {
+                // once past the edge and heading further out, it never
+                // comes back
+                let [dx, dy] = self.step;
+                if (x < 0 && dx < 0)
+                    || (x >= grid.width && dx > 0)
+                    || (y < 0 && dy > 0)
+                    || (y >= grid.height && dy < 0)
+                {
+                    return None;
+                }
+                continue;
+            }
+            let tile = grid.tiles[(x + y * grid.width) as usize];
+            if let Some(door) = grid.door_at(x, y) {
+                // doors only fill part of their tile, so the ray may carry on
+                let hit = hit_door(origin, direction, self.current_tile, door, tile);
+                if hit.is_some() {
+                    return hit;
+                }
+            } else if let Some(segment) = grid.segment_at(x, y) {
+                // and so do thin walls
+                let hit = hit_segment(origin, direction, self.current_tile, segment, tile);
+                if hit.is_some() {
+                    return hit;
+                }
+            } else if !grid.kinds[tile as usize].is_open()
+                || (self.steps && grid.is_step(previous, self.current_tile))
+            {
+                let offset = vec2_scale(direction, distance);
+                // how far into a map tile is the hit
+                let u = match side {
+                    HitSide::X => origin[0] + offset[0],
+                    HitSide::Y => origin[1] - offset[1],
+                }
+                .fract();
+                let normal = match side {
+                    HitSide::X => [0.0, self.step[1] as f32],
+                    HitSide::Y => [-self.step[0] as f32, 0.0],
+                };
+                return Some(Hit {
+                    offset,
+                    side,
+                    tile,
+                    u,
+                    normal,
+                });
             }
         }
     }
+}
+
+/// Intersect a ray with the door across the middle of `door_tile`. Misses if
+/// the ray leaves the tile before reaching the door, or passes through the gap
+/// left by the door sliding open.
+fn hit_door(
+    origin: Vector2<f32>,
+    direction: Vector2<f32>,
+    door_tile: Vector2<i32>,
+    door: &Door,
+    tile: u8,
+) -> Option<Hit> {
+    // Grid y runs the opposite way to the ray's y direction.
+    let (distance, along, side, normal) = if door.horizontal {
+        let distance = (origin[1] - (door_tile[1] as f32 + 0.5)) / direction[1];
+        let x = origin[0] + direction[0] * distance;
+        let normal = [0.0, direction[1].signum()];
+        (distance, x - door_tile[0] as f32, HitSide::X, normal)
+    } else {
+        let distance = (door_tile[0] as f32 + 0.5 - origin[0]) / direction[0];
+        let y = origin[1] - direction[1] * distance;
+        let normal = [-direction[0].signum(), 0.0];
+        (distance, y - door_tile[1] as f32, HitSide::Y, normal)
+    };
+
+    // The door slides towards the start of the tile, leaving a gap at the end.
+    if !distance.is_finite() || distance < 0.0 || !(0.0..1.0 - door.open).contains(&along) {
+        return None;
+    }
+
+    Some(Hit {
+        offset: vec2_scale(direction, distance),
+        side,
+        tile,
+        u: along + door.open,
+        normal,
+    })
+}
+
+/// Intersect a ray with a thin wall running between two points inside
+/// `segment_tile`.
+fn hit_segment(
+    origin: Vector2<f32>,
+    direction: Vector2<f32>,
+    segment_tile: Vector2<i32>,
+    segment: &Segment,
+    tile: u8,
+) -> Option<Hit> {
+    // Work in grid space, where y runs down.
+    let ray = [direction[0], -direction[1]];
+    let start = [
+        segment_tile[0] as f32 + segment.start[0],
+        segment_tile[1] as f32 + segment.start[1],
+    ];
+    let along_wall = [
+        segment.end[0] - segment.start[0],
+        segment.end[1] - segment.start[1],
+    ];
+    let cross = |a: Vector2<f32>, b: Vector2<f32>| a[0] * b[1] - a[1] * b[0];
+
+    // Solve origin + distance * ray = start + along * along_wall
+    let denominator = cross(ray, along_wall);
+    if denominator.abs() < f32::EPSILON {
+        return None;
+    }
+    let to_start = [start[0] - origin[0], start[1] - origin[1]];
+    let distance = cross(to_start, along_wall) / denominator;
+    let along = cross(to_start, ray) / denominator;
+    if distance < 0.0 || !(0.0..=1.0).contains(&along) {
+        return None;
+    }
+
+    // Face the normal back towards the ray
+    let length = f32::hypot(along_wall[0], along_wall[1]);
+    let mut normal = [-along_wall[1] / length, along_wall[0] / length];
+    if normal[0] * ray[0] + normal[1] * ray[1] > 0.0 {
+        normal = [-normal[0], -normal[1]];
+    }
+    let side = if normal[0].abs() > normal[1].abs() {
+        HitSide::Y
+    } else {
+        HitSide::X
+    };
+
+    Some(Hit {
+        offset: vec2_scale(direction, distance),
+        side,
+        tile,
+        // Textures repeat every tile along the wall, like full walls
+        u: (along * length).fract(),
+        normal,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::{Segment, TileKind};
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    /// A 10 by 5 grid with a portal facing east at 3, 2 and one facing west
+    /// at 6, 2, linked to each other.
+    fn portals() -> Grid {
+        let mut grid = Grid::new(10, 5);
+        grid.kinds[1] = TileKind::Portal;
+        for (tile, facing, link) in [([3, 2], [1, 0], [6, 2]), ([6, 2], [-1, 0], [3, 2])] {
+            let i = (tile[0] + tile[1] * grid.width) as usize;
+            grid.tiles[i] = 1;
+            grid.portals.insert(i, Portal { tile, facing, link });
+        }
+        grid
+    }
 
-    if tile_found {
-        return Some((vec2_scale(direction, distance), side));
+    /// A 6 by 6 grid with mirrors in `tiles`.
+    fn mirrors(tiles: impl Iterator<Item = [i32; 2]>) -> Grid {
+        let mut grid = Grid::new(6, 6);
+        grid.kinds[1] = TileKind::Mirror;
+        for [x, y] in tiles {
+            grid.tiles[(x + y * grid.width) as usize] = 1;
+        }
+        grid
     }
 
-    None
+    fn assert_near(actual: Vector2<f32>, expected: Vector2<f32>) {
+        assert!(
+            (actual[0] - expected[0]).abs() < 0.01 && (actual[1] - expected[1]).abs() < 0.01,
+            "{:?} isn't near {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn rays_bounce_off_the_side_of_a_mirror() {
+        // a column of mirrors at x = 5, hit going right and up
+        let grid = mirrors((0..6).map(|y| [5, y]));
+        let mut hits = RayHits::new([2.5, 2.5], [0.8, 0.6], &grid, 20.0).with_bounces(1);
+        let hit = hits.next().unwrap();
+        assert!(matches!(hit.side, HitSide::Y));
+        assert_eq!(hit.normal, [-1.0, 0.0]);
+        let leg = hits.leg();
+        assert_near(leg.origin, [5.0, 0.625]);
+        assert_near(leg.direction, [-0.8, 0.6]);
+        assert!((leg.travelled - 3.125).abs() < 0.01);
+    }
+
+    #[test]
+    fn rays_bounce_off_the_bottom_of_a_mirror() {
+        // a row of mirrors at y = 0, hit going right and up
+        let grid = mirrors((0..6).map(|x| [x, 0]));
+        let mut hits = RayHits::new([2.5, 2.5], [0.6, 0.8], &grid, 20.0).with_bounces(1);
+        let hit = hits.next().unwrap();
+        assert!(matches!(hit.side, HitSide::X));
+        assert_eq!(hit.normal, [0.0, 1.0]);
+        let leg = hits.leg();
+        assert_near(leg.origin, [3.625, 1.0]);
+        assert_near(leg.direction, [0.6, -0.8]);
+        assert!((leg.travelled - 1.875).abs() < 0.01);
+    }
+
+    #[test]
+    fn mirrors_stop_rays_without_bounces() {
+        let grid = mirrors((0..6).map(|y| [5, y]));
+        let mut hits = RayHits::new([2.5, 2.5], [1.0, 0.0], &grid, 20.0).with_bounces(0);
+        assert!(hits.next().is_some());
+        assert_eq!(hits.leg().origin, [2.5, 2.5]);
+        assert_eq!(hits.leg().direction, [1.0, 0.0]);
+    }
+
+    #[test]
+    fn rays_hit_thin_walls_across_a_tile() {
+        // corner to corner, from bottom left to top right of the tile at 2, 1
+        let mut grid = Grid::new(5, 3);
+        grid.kinds[1] = TileKind::Segment;
+        grid.segments[1] = Some(Segment {
+            start: [0.0, 1.0],
+            end: [1.0, 0.0],
+        });
+        grid.tiles[2 + 5] = 1;
+        let hit = RayHits::new([0.5, 1.5], [1.0, 0.0], &grid, 20.0)
+            .next()
+            .unwrap();
+        assert!((vec2_len(hit.offset) - 2.0).abs() < 0.001);
+        // facing back the way the ray came, halfway along the wall
+        assert_near(hit.normal, [-FRAC_1_SQRT_2, -FRAC_1_SQRT_2]);
+        assert!((hit.u - FRAC_1_SQRT_2).abs() < 0.001);
+        assert_eq!(hit.tile, 1);
+    }
+
+    #[test]
+    fn rays_stop_once_they_leave_the_grid() {
+        let grid = Grid::new(3, 3);
+        let mut hits = RayHits::new([1.5, 1.5], [0.6, 0.8], &grid, 1e30);
+        assert!(hits.next().is_none());
+    }
+
+    #[test]
+    fn rays_go_through_the_face_of_a_portal() {
+        let grid = portals();
+        let mut hits = RayHits::new([5.0, 2.5], [-1.0, 0.0], &grid, 20.0).with_bounces(1);
+        let hit = hits.next().unwrap();
+        assert_eq!(hit.normal, [1.0, 0.0]);
+        let leg = hits.leg();
+        assert!((leg.origin[0] - 6.0).abs() < 0.01 && (leg.origin[1] - 2.5).abs() < 0.01);
+        assert_eq!(leg.direction, [-1.0, 0.0]);
+        assert_eq!(leg.travelled, 1.0);
+    }
+
+    #[test]
+    fn the_back_of_a_portal_is_a_wall() {
+        let grid = portals();
+        let mut hits = RayHits::new([1.5, 2.5], [1.0, 0.0], &grid, 20.0).with_bounces(1);
+        let hit = hits.next().unwrap();
+        assert_eq!(hit.normal, [-1.0, 0.0]);
+        assert_eq!(hits.leg().origin, [1.5, 2.5]);
+        assert_eq!(hits.leg().travelled, 0.0);
+    }
 }
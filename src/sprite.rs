@@ -0,0 +1,23 @@
+/// An object in the world that isn't a wall, drawn as a billboard that always
+/// faces the camera.
+#[derive(Copy, Clone)]
+pub struct Sprite {
+    pub x: f32,
+    pub y: f32,
+    /// Index of the sprite's texture in the viewport's `TextureTable`.
+    pub texture: usize,
+    /// Which storey of the level the sprite is on, counting up from 0.
+    pub storey: usize,
+}
+
+impl Sprite {
+    /// A sprite on the bottom storey.
+    pub fn new(x: f32, y: f32, texture: usize) -> Sprite {
+        Sprite {
+            x,
+            y,
+            texture,
+            storey: 0,
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use image::ImageResult;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// How textures are filtered when they're drawn.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sampling {
+    /// Blend the four nearest texels rather than taking the nearest one.
+    pub bilinear: bool,
+    /// Draw distant surfaces from smaller copies of their textures, which
+    /// stops them shimmering as the view moves.
+    pub mipmaps: bool,
+}
+
+/// An RGBA8 image that walls can be textured with.
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<u8>,
+    /// Mipmaps of the texture, each half the size of the one before, down to
+    /// a single texel.
+    mips: Vec<Texture>,
+}
+
+impl Texture {
+    /// Make a texture from rows of RGBA8 pixels, generating its mipmaps.
+    pub fn new(width: usize, height: usize, pixels: Vec<u8>) -> Texture {
+        let mut mips: Vec<Texture> = Vec::new();
+        let mut texture = Texture {
+            width,
+            height,
+            pixels,
+            mips: Vec::new(),
+        };
+        while let Some(mip) = mips.last().unwrap_or(&texture).half_size() {
+            mips.push(mip);
+        }
+        texture.mips = mips;
+        texture
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> ImageResult<Texture> {
+        let image = image::open(path)?.to_rgba8();
+        Ok(Texture::new(
+            image.width() as usize,
+            image.height() as usize,
+            image.into_raw(),
+        ))
+    }
+
+    /// Cut a sprite sheet with `frames` frames side by side into separate
+    /// textures, or `None` if it isn't a whole number of frames wide.
+    pub fn split(&self, frames: usize) -> Option<Vec<Texture>> {
+        if frames == 0 || self.width % frames != 0 {
+            return None;
+        }
+        let width = self.width / frames;
+        let textures = (0..frames)
+            .map(|frame| {
+                let mut pixels = Vec::with_capacity(width * self.height * 4);
+                for y in 0..self.height {
+                    let start = (frame * width + y * self.width) * 4;
+                    pixels.extend_from_slice(&self.pixels[start..start + width * 4]);
+                }
+                Texture::new(width, self.height, pixels)
+            })
+            .collect();
+        Some(textures)
+    }
+
+    /// Sample the texel at `x`, `y`, both in the range `[0, 1)`.
+    pub fn sample(&self, x: f32, y: f32) -> [u8; 4] {
+        let tex_x = ((x * self.width as f32) as usize).min(self.width - 1);
+        let tex_y = ((y * self.height as f32) as usize).min(self.height - 1);
+        self.texel(tex_x, tex_y)
+    }
+
+    /// Sample the texture at `x`, `y`, both in the range `[0, 1)`, filtered
+    /// by `sampling`. `texels_per_pixel` is how many texels of the full size
+    /// texture each pixel covers, which picks the mipmap to sample.
+    pub fn sample_with(
+        &self,
+        x: f32,
+        y: f32,
+        texels_per_pixel: f32,
+        sampling: Sampling,
+    ) -> [u8; 4] {
+        let texture = if sampling.mipmaps {
+            self.mip(texels_per_pixel)
+        } else {
+            self
+        };
+        if sampling.bilinear {
+            texture.sample_bilinear(x, y)
+        } else {
+            texture.sample(x, y)
+        }
+    }
+
+    /// Blend the four texels nearest `x`, `y`. The texture repeats, so
+    /// texels on one edge blend with those on the opposite edge.
+    fn sample_bilinear(&self, x: f32, y: f32) -> [u8; 4] {
+        // Texel centres are half a texel in from their corners
+        let x = x * self.width as f32 - 0.5;
+        let y = y * self.height as f32 - 0.5;
+        let (left, top) = (x.floor(), y.floor());
+        let (across, down) = (x - left, y - top);
+        let wrap = |v: f32, size: usize| (v as i64).rem_euclid(size as i64) as usize;
+        let (left, right) = (wrap(left, self.width), wrap(left + 1.0, self.width));
+        let (top, bottom) = (wrap(top, self.height), wrap(top + 1.0, self.height));
+        let [top_left, top_right] = [self.texel(left, top), self.texel(right, top)];
+        let [bottom_left, bottom_right] = [self.texel(left, bottom), self.texel(right, bottom)];
+
+        let mut c: [u8; 4] = [0; 4];
+        for (i, c) in c.iter_mut().enumerate() {
+            let upper = top_left[i] as f32 + (top_right[i] as f32 - top_left[i] as f32) * across;
+            let lower =
+                bottom_left[i] as f32 + (bottom_right[i] as f32 - bottom_left[i] as f32) * across;
+            *c = (upper + (lower - upper) * down).round() as u8;
+        }
+        c
+    }
+
+    /// The mipmap closest to one texel per pixel when the full size texture
+    /// is drawn at `texels_per_pixel`.
+    fn mip(&self, texels_per_pixel: f32) -> &Texture {
+        let level = texels_per_pixel.log2().round();
+        if level.is_nan() || level < 1.0 {
+            return self;
+        }
+        let level = level as usize - 1;
+        self.mips
+            .get(level)
+            .or_else(|| self.mips.last())
+            .unwrap_or(self)
+    }
+
+    /// The texel at column `x` of row `y`.
+    fn texel(&self, x: usize, y: usize) -> [u8; 4] {
+        let start = x * 4 + y * self.width * 4;
+        let mut c: [u8; 4] = [0; 4];
+        c.copy_from_slice(&self.pixels[start..start + 4]);
+        c
+    }
+
+    /// The next mipmap down from this texture, half the size with each texel
+    /// the average of the ones it covers, or `None` once it's a single texel.
+    /// Colours are weighted by alpha so transparent texels don't darken the
+    /// edges of what's around them.
+    fn half_size(&self) -> Option<Texture> {
+        if self.width <= 1 && self.height <= 1 {
+            return None;
+        }
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut pixels = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0_u32; 4];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let c = self.texel(
+                        (x * 2 + dx).min(self.width - 1),
+                        (y * 2 + dy).min(self.height - 1),
+                    );
+                    let alpha = c[3] as u32;
+                    for i in 0..3 {
+                        sum[i] += c[i] as u32 * alpha;
+                    }
+                    sum[3] += alpha;
+                }
+                if sum[3] == 0 {
+                    pixels.extend_from_slice(&[0; 4]);
+                } else {
+                    let alpha = sum[3];
+                    pixels.extend(sum[0..3].iter().map(|&c| ((c + alpha / 2) / alpha) as u8));
+                    pixels.push(((alpha + 2) / 4) as u8);
+                }
+            }
+        }
+        Some(Texture {
+            width,
+            height,
+            pixels,
+            mips: Vec::new(),
+        })
+    }
+}
+
+/// A texture that cycles through several frames.
+struct Animation {
+    /// Indices of the frames in the `TextureTable`, in order.
+    frames: Vec<usize>,
+    /// Frames per second.
+    frame_rate: f32,
+}
+
+/// All loaded textures, along with which one each wall, floor and ceiling
+/// tile value is drawn with.
+#[derive(Default)]
+pub struct TextureTable {
+    textures: Vec<Texture>,
+    /// Animations keyed by the index of their first frame, which stands in
+    /// for the whole animation.
+    animations: HashMap<usize, Animation>,
+    /// Seconds the animations have been running.
+    time: f32,
+    walls: HashMap<u8, usize>,
+    floors: HashMap<u8, usize>,
+    ceilings: HashMap<u8, usize>,
+}
+
+impl TextureTable {
+    pub fn new() -> TextureTable {
+        TextureTable::default()
+    }
+
+    /// Add a texture to the table, returning its index.
+    pub fn add(&mut self, texture: Texture) -> usize {
+        self.textures.push(texture);
+        self.textures.len() - 1
+    }
+
+    /// Add an animation that plays `frames` at `frame_rate` frames per second,
+    /// returning the index that stands for it. Anything drawn with that index
+    /// shows the current frame.
+    pub fn add_animation(&mut self, frames: Vec<Texture>, frame_rate: f32) -> usize {
+        let first = self.textures.len();
+        let frames = frames
+            .into_iter()
+            .map(|frame| self.add(frame))
+            .collect::<Vec<_>>();
+        self.animations
+            .insert(first, Animation { frames, frame_rate });
+        first
+    }
+
+    /// Advance the animations by `delta` seconds.
+    pub fn update(&mut self, delta: f32) {
+        self.time += delta;
+    }
+
+    /// The index of the frame to show for the texture at `index`, which is
+    /// `index` itself unless it's an animation.
+    fn frame(&self, index: usize) -> usize {
+        match self.animations.get(&index) {
+            Some(animation) if !animation.frames.is_empty() => {
+                let frame = (self.time * animation.frame_rate) as usize;
+                animation.frames[frame % animation.frames.len()]
+            }
+            _ => index,
+        }
+    }
+
+    /// Draw walls with the tile value `tile` using the texture at `index`.
+    pub fn set_wall(&mut self, tile: u8, index: usize) {
+        self.walls.insert(tile, index);
+    }
+
+    /// Draw floors with the floor value `tile` using the texture at `index`.
+    pub fn set_floor(&mut self, tile: u8, index: usize) {
+        self.floors.insert(tile, index);
+    }
+
+    /// Draw ceilings with the ceiling value `tile` using the texture at `index`.
+    pub fn set_ceiling(&mut self, tile: u8, index: usize) {
+        self.ceilings.insert(tile, index);
+    }
+
+    pub fn get(&self, index: usize) -> &Texture {
+        &self.textures[self.frame(index)]
+    }
+
+    /// The texture for a wall tile. Tiles without their own texture use the
+    /// first texture added to the table.
+    pub fn wall(&self, tile: u8) -> &Texture {
+        self.get(self.walls.get(&tile).copied().unwrap_or(0))
+    }
+
+    /// The texture for a floor value, if it has one.
+    pub fn floor(&self, tile: u8) -> Option<&Texture> {
+        self.floors.get(&tile).map(|&i| self.get(i))
+    }
+
+    /// The texture for a ceiling value, if it has one.
+    pub fn ceiling(&self, tile: u8) -> Option<&Texture> {
+        self.ceilings.get(&tile).map(|&i| self.get(i))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.textures.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `width` by `height` texture filled with `colour`.
+    fn filled(width: usize, height: usize, colour: [u8; 4]) -> Texture {
+        Texture::new(width, height, colour.repeat(width * height))
+    }
+
+    #[test]
+    fn picks_the_mip_closest_to_one_texel_per_pixel() {
+        let texture = filled(4, 4, [255; 4]);
+        assert_eq!(texture.mip(1.0).width, 4);
+        assert_eq!(texture.mip(2.0).width, 2);
+        // and the smallest mip for anything further away than that
+        assert_eq!(texture.mip(1e6).width, 1);
+        assert_eq!(texture.mip(0.0).width, 4);
+    }
+
+    #[test]
+    fn mips_ignore_the_colour_of_transparent_texels() {
+        // one opaque red texel among transparent white ones
+        let mut pixels = [255, 255, 255, 0].repeat(4);
+        pixels[..4].copy_from_slice(&[255, 0, 0, 255]);
+        let texture = Texture::new(2, 2, pixels);
+        assert_eq!(texture.mip(2.0).texel(0, 0), [255, 0, 0, 64]);
+    }
+
+    #[test]
+    fn bilinear_sampling_wraps_at_the_edges() {
+        // black on the left and white on the right
+        let texture = Texture::new(2, 1, [0, 0, 0, 255, 255, 255, 255, 255].to_vec());
+        // the left edge is halfway between the left texel and the right one
+        // it wraps around to
+        assert_eq!(texture.sample_bilinear(0.0, 0.5), [128, 128, 128, 255]);
+        assert_eq!(texture.sample_bilinear(1.0, 0.5), [128, 128, 128, 255]);
+        // texel centres are the texels themselves
+        assert_eq!(texture.sample_bilinear(0.25, 0.5), [0, 0, 0, 255]);
+        assert_eq!(texture.sample_bilinear(0.75, 0.5), [255, 255, 255, 255]);
+    }
+}
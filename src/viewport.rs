@@ -1,10 +1,138 @@
-use crate::drawing::pixel;
-use crate::grid::Grid;
+use crate::drawing::{blend, Frame};
+use crate::fog::Fog;
+use crate::grid::{Grid, TileKind, OPENING};
+use crate::light::shade;
 use crate::player::Player;
-use crate::ray::{cast_ray, HitSide};
-use crate::{line, rect_filled, Point, HEIGHT, WIDTH};
-use image::GenericImageView;
-use vecmath::{vec2_len, Vector2};
+use crate::ray::{Hit, Leg, RayHits};
+use crate::sprite::Sprite;
+use crate::texture::{Sampling, Texture, TextureTable};
+use crate::{line, Point};
+use rayon::prelude::*;
+use std::f32::consts::{FRAC_PI_2, TAU};
+use std::ops::Range;
+use vecmath::{vec2_len, vec2_scale, Vector2};
+
+/// How far rays are cast, in tiles, unless the level says otherwise.
+pub const DEFAULT_VIEW_DISTANCE: f32 = 10.0;
+
+/// Colour of untextured floors.
+const FLOOR_COLOUR: [u8; 4] = [128, 128, 128, 255];
+/// Colour of the sky where there is no ceiling.
+const SKY_COLOUR: [u8; 4] = [0, 128, 175, 255];
+
+/// How many mirrors and portals a ray can bounce off or go through. Any
+/// past the last bounce show only their texture.
+const MAX_BOUNCES: u32 = 4;
+
+/// How many columns of the view each parallel job draws.
+const BAND_WIDTH: usize = 16;
+
+/// The ray cast for one column of the viewport.
+#[derive(Clone)]
+pub struct Column {
+    /// Direction of the ray, with y pointing up the screen.
+    pub direction: Vector2<f32>,
+    /// Every wall the ray hit, nearest first. The ray carries on past walls
+    /// too short to hide everything behind them, and through see-through
+    /// walls.
+    pub slices: Vec<WallSlice>,
+    /// The straight stretches of the ray, one more than the number of
+    /// mirrors it bounced off and portals it went through.
+    pub legs: Vec<Leg>,
+}
+
+/// A wall, or the face of a step, hit by a column's ray.
+#[derive(Copy, Clone)]
+pub struct WallSlice {
+    pub hit: Hit,
+    /// Distance to the hit along the player's facing direction rather than
+    /// along the ray, which avoids a fisheye effect.
+    pub depth: f32,
+    /// Brightness of the face that was hit, from 0 to 1.
+    pub light: f32,
+    /// Height of the bottom of the face, in wall heights. Walls run down to
+    /// the floor in front of them, covering any step up to their own floor.
+    pub bottom: f32,
+    /// Height of the top of the face, in wall heights.
+    pub top: f32,
+    pub face: Face,
+    /// Whether the wall can be seen through, including mirrors the ray
+    /// bounced off and portals it went through.
+    pub transparent: bool,
+    /// Which of the column's legs the hit is on.
+    pub leg: usize,
+    /// Height of the floor past the face, where the ray carries on. The
+    /// floor of a solid wall is its top.
+    pub floor: f32,
+    /// Height of the ceiling past the face.
+    pub ceiling: f32,
+}
+
+/// What a wall slice is the face of.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Face {
+    /// A wall, drawn with its tile's wall texture.
+    Wall,
+    /// A step up in the floor, drawn with the texture of the floor value at
+    /// the top of it.
+    Step(u8),
+    /// A step down in the ceiling, drawn with the texture of the ceiling
+    /// value at the bottom of it.
+    Lintel(u8),
+}
+
+/// A stretch of a column's ray between two faces, where the floor and
+/// ceiling stay the same height.
+struct Stretch {
+    /// Depth of the start and end of the stretch.
+    near: f32,
+    far: f32,
+    floor: f32,
+    ceiling: f32,
+}
+
+/// What a row of the floor or ceiling shows.
+enum Surface {
+    /// Floor or ceiling of this colour, hiding anything further away.
+    Solid([u8; 4]),
+    /// A hole through to another storey, drawn underneath.
+    Hole,
+    /// The sky, which anything further away is drawn over.
+    Sky([u8; 4]),
+}
+
+/// Where a sprite lands in the view, in viewport pixels.
+struct SpriteProjection<'a> {
+    texture: &'a Texture,
+    /// Distance in front of the player, for comparing against walls.
+    forward: f32,
+    /// Brightness of the tile the sprite stands in.
+    light: f32,
+    left: f32,
+    top: f32,
+    width: f32,
+    height: f32,
+    start_x: i32,
+    end_x: i32,
+    start_y: i32,
+    end_y: i32,
+}
+
+/// One storey's worth of the view. The storeys above and below the player are
+/// drawn first, and only show where the player's storey has holes.
+struct Layer<'a> {
+    grid: &'a Grid,
+    columns: &'a [Column],
+    sprites: Vec<SpriteProjection<'a>>,
+    /// How far above this storey's floor the eye is, in wall heights.
+    eye_z: f32,
+    /// Whether holes in the floor are left for the storey below to show
+    /// through, rather than drawn as untextured floor.
+    see_down: bool,
+    /// The storey above, if the ceiling is left open wherever its floor has a
+    /// hole.
+    above: Option<&'a Grid>,
+}
 
 pub struct Viewport {
     x_offset: i32,
@@ -12,69 +140,325 @@ pub struct Viewport {
     width: i32,
     height: i32,
     fov: f32,
-    texture: Vec<u8>,
-    tex_width: usize,
-    tex_height: usize,
+    /// How far rays are cast before giving up, in tiles.
+    view_distance: f32,
+    fog: Fog,
+    /// Index of the panoramic texture drawn where there's no ceiling, or
+    /// `None` for a plain sky colour.
+    sky: Option<usize>,
+    textures: TextureTable,
+    /// How textures are filtered.
+    sampling: Sampling,
 }
 
 impl Viewport {
     pub fn new() -> Viewport {
         Viewport {
-            x_offset: HEIGHT + 20,
-            y_offset: 1,
-            width: WIDTH - 10 - (HEIGHT + 20),
-            height: HEIGHT - 1,
-            fov: 2.0 * (0.66 / 1.0 as f32).atan(), // 66 degrees
-            texture: Vec::new(),
-            tex_width: 0,
-            tex_height: 0,
+            x_offset: 0,
+            y_offset: 0,
+            width: 1,
+            height: 1,
+            fov: 2.0 * (0.66_f32 / 1.0).atan(), // 66 degrees
+            view_distance: DEFAULT_VIEW_DISTANCE,
+            fog: Fog::none(),
+            sky: None,
+            textures: TextureTable::new(),
+            sampling: Sampling::default(),
         }
     }
 
-    pub fn init(&mut self) {
-        let grid_image = image::open("assets/brick_2.png").unwrap();
-        self.tex_width = grid_image.width() as usize;
-        self.tex_height = grid_image.height() as usize;
-        self.texture
-            .resize((grid_image.width() * grid_image.height() * 4) as usize, 0);
+    /// Place the viewport to the right of a `minimap_size` wide minimap,
+    /// filling the rest of a `frame_width` by `frame_height` frame. Frames
+    /// too small for both squeeze the viewport down to a single column, which
+    /// always stays inside the frame.
+    pub fn layout(&mut self, frame_width: i32, frame_height: i32, minimap_size: i32) {
+        self.x_offset = (minimap_size + 20).min(frame_width - 1).max(0);
+        self.y_offset = 1;
+        self.width = (frame_width - 10 - self.x_offset)
+            .min(frame_width - self.x_offset)
+            .max(1);
+        self.height = (frame_height - 1).max(1);
+    }
 
-        grid_image.pixels().for_each(|pixel| {
-            let x = pixel.0 as usize;
-            let y = pixel.1 as usize;
-            let i = x * 4 + (y * grid_image.width() as usize) * 4;
-            self.texture[i..i + 4].copy_from_slice(&pixel.2 .0);
-        });
+    pub fn width(&self) -> i32 {
+        self.width
     }
 
-    pub fn draw(&self, frame: &mut [u8], player: &Player, grid: &Grid) {
-        let grid_colour = [0, 200, 0, 255];
-        // sky
-        rect_filled(
-            frame,
-            &Point {
-                x: self.x_offset,
-                y: self.y_offset,
-            },
-            &Point {
-                x: self.x_offset + self.width,
-                y: self.y_offset + self.height / 2,
-            },
-            [0, 128, 175, 255],
-        );
-        // floor
-        rect_filled(
-            frame,
-            &Point {
-                x: self.x_offset,
-                y: self.y_offset + self.height / 2,
-            },
-            &Point {
-                x: self.x_offset + self.width,
-                y: self.y_offset + self.height,
-            },
-            [128, 128, 128, 255],
-        );
+    pub fn fov(&self) -> f32 {
+        self.fov
+    }
+
+    pub fn view_distance(&self) -> f32 {
+        self.view_distance
+    }
+
+    /// Set how far rays are cast, in tiles. Walls further away than this
+    /// aren't drawn, so it's best paired with fog that is solid by then.
+    pub fn set_view_distance(&mut self, view_distance: f32) {
+        self.view_distance = view_distance;
+    }
+
+    pub fn fog(&self) -> &Fog {
+        &self.fog
+    }
+
+    pub fn set_fog(&mut self, fog: Fog) {
+        self.fog = fog;
+    }
+
+    pub fn sky(&self) -> Option<usize> {
+        self.sky
+    }
+
+    /// Draw the sky with the texture at `index` in the texture table, which
+    /// wraps once around the player and runs from the horizon at the bottom
+    /// to straight up at the top.
+    pub fn set_sky(&mut self, sky: Option<usize>) {
+        self.sky = sky;
+    }
+
+    /// Replace the textures walls, floors, ceilings and sprites are drawn
+    /// with.
+    pub fn set_textures(&mut self, textures: TextureTable) {
+        self.textures = textures;
+    }
+
+    pub fn textures(&self) -> &TextureTable {
+        &self.textures
+    }
+
+    pub fn textures_mut(&mut self) -> &mut TextureTable {
+        &mut self.textures
+    }
+
+    pub fn sampling(&self) -> Sampling {
+        self.sampling
+    }
+
+    /// Choose how textures are filtered. Mipmaps and bilinear filtering look
+    /// smoother, but cost more to draw.
+    pub fn set_sampling(&mut self, sampling: Sampling) {
+        self.sampling = sampling;
+    }
+
+    /// The row of the view the horizon is on, shifted up or down by the
+    /// player's pitch. May be outside the view when looking steeply.
+    fn horizon(&self, player: &Player) -> i32 {
+        // Walls one unit tall at distance d are height / d pixels tall, so
+        // the vertical focal length is the height of the view.
+        self.height / 2 + (player.pitch.tan() * self.height as f32) as i32
+    }
+
+    /// How far across the view plane each column is, one unit in front of
+    /// the player.
+    fn increment(&self) -> f32 {
+        (1.0 * (self.fov).sin()) / (self.width as f32 / 2.0)
+    }
+
+    /// Cast one ray per column of the viewport, in parallel. The results are
+    /// shared by the 3D view and the minimap.
+    pub fn cast(&self, player: &Player, grid: &Grid) -> Vec<Column> {
+        self.cast_storey(player, grid, player.eye_z())
+    }
+
+    /// Cast rays on a storey whose floor is `eye_z` below the eye.
+    fn cast_storey(&self, player: &Player, grid: &Grid, eye_z: f32) -> Vec<Column> {
+        let origin: Vector2<f32> = [player.x, player.y];
+        let increment = self.increment();
+        // Nothing can be seen past a wall this tall, unless the eye is above
+        // it
+        let tallest = grid.tallest_wall();
+
+        (0..self.width)
+            .into_par_iter()
+            .map(|x| {
+                let ray_angle = -(increment * (x - self.width / 2) as f32).atan() + player.angle;
+                let direction = [ray_angle.cos(), ray_angle.sin()];
+                let mut slices = Vec::new();
+                let mut hits = RayHits::new(origin, direction, grid, self.view_distance)
+                    .with_bounces(MAX_BOUNCES)
+                    .with_steps();
+                let mut legs = vec![hits.leg()];
+                // Heights of the floor and ceiling the ray is passing over
+                let [x, y] = [origin[0].floor() as i32, origin[1].floor() as i32];
+                let mut floor = grid.floor_height_at(x, y);
+                let mut ceiling = grid.ceiling_height_at(x, y);
+                while let Some(hit) = hits.next() {
+                    let leg = legs.len() - 1;
+                    let current = legs[leg];
+                    let distance = current.travelled + vec2_len(hit.offset);
+                    let depth = distance * (player.angle - ray_angle).cos();
+                    // A wall is lit by the tile in front of the face that was
+                    // hit, so step back out of the wall to find it.
+                    let [x, y] = current.point(hit.offset);
+                    let x = x - current.direction[0] * 0.01;
+                    let y = y + current.direction[1] * 0.01;
+                    let light = grid.light_at(x.floor() as i32, y.floor() as i32);
+                    // and the tile that was hit is just past it
+                    let x = (x + current.direction[0] * 0.02).floor() as i32;
+                    let y = (y - current.direction[1] * 0.02).floor() as i32;
+                    // Mirrors the ray bounced off and portals it went through
+                    // show what is on the other side
+                    let bounced = hits.leg() != current;
+                    if bounced {
+                        legs.push(hits.leg());
+                    }
+                    let transparent = grid.is_transparent(hit.tile) || bounced;
+                    let is_step = grid.kinds[hit.tile as usize].is_open();
+                    let face_floor = grid.floor_height_at(x, y);
+                    let top = if is_step {
+                        face_floor
+                    } else {
+                        face_floor + grid.heights[hit.tile as usize]
+                    };
+
+                    // The ray carries on over the floor and ceiling past the
+                    // face, or wherever it bounced to, and along the tops of
+                    // solid walls.
+                    let (next_floor, next_ceiling) = if bounced {
+                        let [x, y] = hits.leg().origin;
+                        let [x, y] = [x.floor() as i32, y.floor() as i32];
+                        (grid.floor_height_at(x, y), grid.ceiling_height_at(x, y))
+                    } else if grid.kinds[hit.tile as usize] == TileKind::Wall {
+                        (top, grid.ceiling_height_at(x, y))
+                    } else {
+                        (face_floor, grid.ceiling_height_at(x, y))
+                    };
+                    let slice = |bottom, top, face| WallSlice {
+                        hit,
+                        depth,
+                        light,
+                        bottom,
+                        top,
+                        face,
+                        transparent,
+                        leg,
+                        floor: next_floor,
+                        ceiling: next_ceiling,
+                    };
+                    if is_step {
+                        // Steps only show going up, or the ceiling coming down
+                        let face_ceiling = grid.ceiling_height_at(x, y);
+                        if face_ceiling < ceiling && grid.ceiling_at(x, y) != 0 {
+                            let lintel = Face::Lintel(grid.ceiling_at(x, y));
+                            slices.push(slice(face_ceiling, ceiling, lintel));
+                        }
+                        let step = Face::Step(grid.floor_at(x, y));
+                        slices.push(slice(floor, top.max(floor), step));
+                    } else {
+                        slices.push(slice(floor, top, Face::Wall));
+                    }
+                    floor = next_floor;
+                    ceiling = next_ceiling;
+
+                    if !is_step && top >= tallest && top > eye_z && !transparent {
+                        break;
+                    }
+                }
+                Column {
+                    direction,
+                    slices,
+                    legs,
+                }
+            })
+            .collect()
+    }
+
+    /// Draw the view from `player`, who is on storey `storey` of `storeys`.
+    /// `columns` are the rays cast on that storey.
+    pub fn draw(
+        &self,
+        frame: &mut Frame,
+        player: &Player,
+        storeys: &[Grid],
+        storey: usize,
+        sprites: &[Sprite],
+        columns: &[Column],
+    ) {
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let grid = &storeys[storey];
+        let eye_z = player.eye_z();
+
+        // The storeys either side are only seen through holes in the floor
+        // of this one and of the one above, so they're only cast when there
+        // are any.
+        let below = storey
+            .checked_sub(1)
+            .map(|i| &storeys[i])
+            .filter(|_| grid.has_openings());
+        let above = storeys.get(storey + 1).filter(|above| above.has_openings());
+        let below_columns = below.map(|below| self.cast_storey(player, below, eye_z + 1.0));
+        let above_columns = above.map(|above| self.cast_storey(player, above, eye_z - 1.0));
+
+        let mut layers = Vec::new();
+        if let (Some(below), Some(columns)) = (below, &below_columns) {
+            let eye_z = eye_z + 1.0;
+            layers.push(Layer {
+                grid: below,
+                columns,
+                sprites: self.project_sprites(player, below, eye_z, sprites, storey - 1),
+                eye_z,
+                see_down: false,
+                above: None,
+            });
+        }
+        if let (Some(above), Some(columns)) = (above, &above_columns) {
+            let eye_z = eye_z - 1.0;
+            layers.push(Layer {
+                grid: above,
+                columns,
+                sprites: self.project_sprites(player, above, eye_z, sprites, storey + 1),
+                eye_z,
+                see_down: false,
+                above: None,
+            });
+        }
+        layers.push(Layer {
+            grid,
+            columns,
+            sprites: self.project_sprites(player, grid, eye_z, sprites, storey),
+            eye_z,
+            see_down: below.is_some(),
+            above,
+        });
+
+        // Render the view with columns laid out one after another rather than
+        // rows, so each band of columns is one contiguous slice that can be
+        // drawn on its own thread.
+        let mut view = vec![0; width * height * 4];
+        view.par_chunks_mut(height * 4 * BAND_WIDTH)
+            .enumerate()
+            .for_each(|(band, pixels)| {
+                let mut depth = vec![0.0; height];
+                for (i, column_pixels) in pixels.chunks_mut(height * 4).enumerate() {
+                    let x = band * BAND_WIDTH + i;
+                    for layer in &layers {
+                        self.draw_column(column_pixels, &mut depth, x as i32, layer, player);
+                    }
+                }
+            });
+
+        // Copy the view into the frame a row at a time, leaving off anything
+        // that doesn't fit.
+        let x_offset = self.x_offset as usize;
+        let copy_width = width.min((frame.width as usize).saturating_sub(x_offset));
+        frame
+            .pixels
+            .par_chunks_mut(frame.width as usize * 4)
+            .skip(self.y_offset as usize)
+            .take(height)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for x in 0..copy_width {
+                    let from = (x * height + y) * 4;
+                    let to = (x_offset + x) * 4;
+                    row[to..to + 4].copy_from_slice(&view[from..from + 4]);
+                }
+            });
+
         // border
+        let grid_colour = [0, 200, 0, 255];
         line(
             frame,
             &Point {
@@ -123,62 +507,397 @@ impl Viewport {
             },
             grid_colour,
         );
+    }
 
-        // walls
-        let origin: Vector2<f32> = [player.x, player.y];
-        let increment = (1.0 * (self.fov).sin()) / (self.width as f32 / 2.0);
-
-        for x in 0..self.width {
-            let ray_angle = -(increment * (x - self.width / 2) as f32).atan() + player.angle;
-
-            let ray_direction = [ray_angle.cos(), ray_angle.sin()];
-            let hit = cast_ray(origin, ray_direction, grid);
-
-            match hit {
-                None => (),
-                Some((h, s)) => {
-                    let direction_angle = (player.angle - ray_angle).cos();
-                    let distance = vec2_len(h) * direction_angle;
-                    let line_height = (self.height as f32 / distance) as i32;
-
-                    // how far into a map file is the hit
-                    // lets us sample the texture at the right x coordinate
-                    let x_texture = match s {
-                        HitSide::X => origin[0] + h[0],
-                        HitSide::Y => origin[1] - h[1],
+    /// Draw one column of a layer of the view. Working out from the player,
+    /// each stretch of floor and ceiling and then the solid face at the end of
+    /// it are drawn only where nothing nearer has been, leaving holes into
+    /// other storeys alone. See-through walls and sprites in front of those
+    /// are then blended over the top, furthest first. `depth` is scratch space
+    /// for the distance to the solid surface at each row.
+    fn draw_column(
+        &self,
+        pixels: &mut [u8],
+        depth: &mut [f32],
+        x: i32,
+        layer: &Layer,
+        player: &Player,
+    ) {
+        let horizon = self.horizon(player);
+        let eye_z = layer.eye_z;
+        let column = &layer.columns[x as usize];
+        depth.fill(f32::INFINITY);
+
+        // Direction of the ray scaled so that it is one unit long along the
+        // player's facing direction, for finding where rows meet the floor.
+        let (sin, cos) = player.angle.sin_cos();
+        let across = self.increment() * (x - self.width / 2) as f32;
+        let ray = [cos + across * sin, sin - across * cos];
+
+        let [start_x, start_y] = column.legs[0].origin;
+        let (start_x, start_y) = (start_x.floor() as i32, start_y.floor() as i32);
+        let mut stretch = Stretch {
+            near: 0.0,
+            far: f32::INFINITY,
+            floor: layer.grid.floor_height_at(start_x, start_y),
+            ceiling: layer.grid.ceiling_height_at(start_x, start_y),
+        };
+        for slice in &column.slices {
+            stretch.far = slice.depth;
+            self.draw_stretch(pixels, depth, &stretch, column, layer, horizon, ray);
+            if !slice.transparent {
+                for y in self.slice_rows(slice, horizon, eye_z) {
+                    if depth[y as usize] != f32::INFINITY {
+                        continue;
                     }
-                    .fract();
-
-                    let height_offset = (self.height - line_height) / 2;
-                    for y in 0..line_height {
-                        pixel(
-                            frame,
-                            x + self.x_offset,
-                            self.y_offset + height_offset + y,
-                            self.sample_texture(x_texture, y as f32 / line_height as f32, s),
-                        );
+                    depth[y as usize] = slice.depth;
+                    let c = self.sample_slice(slice, y, horizon, eye_z);
+                    let i = y as usize * 4;
+                    pixels[i..i + 4].copy_from_slice(&c);
+                }
+            }
+            stretch = Stretch {
+                near: slice.depth,
+                far: f32::INFINITY,
+                floor: slice.floor,
+                ceiling: slice.ceiling,
+            };
+        }
+        self.draw_stretch(pixels, depth, &stretch, column, layer, horizon, ray);
+
+        // Sprites are only seen directly, not in mirrors or through portals,
+        // so the first of those hides everything behind it.
+        let bounce_depth = column
+            .legs
+            .get(1)
+            .map_or(f32::INFINITY, |leg| leg.travelled / vec2_len(ray));
+
+        // Merge the see-through walls and the sprites in this column into one
+        // list, furthest first, so each is blended over everything behind it.
+        // The slices are nearest first and the sprites already furthest first.
+        let mut windows = column
+            .slices
+            .iter()
+            .rev()
+            .filter(|slice| slice.transparent)
+            .peekable();
+        let mut sprites = layer
+            .sprites
+            .iter()
+            .filter(|sprite| x >= sprite.start_x && x < sprite.end_x)
+            .filter(|sprite| sprite.forward < bounce_depth)
+            .peekable();
+        loop {
+            let window_first = match (windows.peek(), sprites.peek()) {
+                (None, None) => break,
+                (Some(window), Some(sprite)) => window.depth > sprite.forward,
+                (window, _) => window.is_some(),
+            };
+            if window_first {
+                // the loop ends before both are empty, so this is always set
+                let slice = windows.next().unwrap();
+                for y in self.slice_rows(slice, horizon, eye_z) {
+                    if depth[y as usize] > slice.depth {
+                        let c = self.sample_slice(slice, y, horizon, eye_z);
+                        let i = y as usize * 4;
+                        let under = [pixels[i], pixels[i + 1], pixels[i + 2], pixels[i + 3]];
+                        pixels[i..i + 4].copy_from_slice(&blend(under, c));
                     }
                 }
+            } else {
+                let sprite = sprites.next().unwrap();
+                self.draw_sprite_column(pixels, depth, x, sprite);
+            }
+        }
+    }
+
+    /// The rows of the view covered by a wall slice, placed around the
+    /// horizon by how far above or below the eye its top and bottom are.
+    fn slice_rows(&self, slice: &WallSlice, horizon: i32, eye_z: f32) -> Range<i32> {
+        let scale = self.height as f32 / slice.depth;
+        let top = horizon as f32 - (slice.top - eye_z) * scale;
+        let bottom = horizon as f32 + (eye_z - slice.bottom) * scale;
+        (top.ceil() as i32).max(0)..(bottom.ceil() as i32).min(self.height)
+    }
+
+    /// The colour of row `y` of a wall slice.
+    fn sample_slice(&self, slice: &WallSlice, y: i32, horizon: i32, eye_z: f32) -> [u8; 4] {
+        // Textures repeat every wall height, starting from height 0
+        let z = eye_z + (horizon - y) as f32 * slice.depth / self.height as f32;
+        let texture = match slice.face {
+            Face::Wall => Some(self.textures.wall(slice.hit.tile)),
+            Face::Step(floor) => self.textures.floor(floor),
+            Face::Lintel(ceiling) => self.textures.ceiling(ceiling),
+        };
+        self.sample_texture(
+            texture,
+            slice.hit.u,
+            (1.0 - z).rem_euclid(1.0),
+            slice.hit.normal,
+            slice.light,
+            slice.depth,
+        )
+    }
+
+    /// Blend column `x` of a sprite over the pixels of the view it isn't
+    /// hidden behind.
+    fn draw_sprite_column(
+        &self,
+        pixels: &mut [u8],
+        depth: &[f32],
+        x: i32,
+        sprite: &SpriteProjection,
+    ) {
+        let tex_x = (x as f32 - sprite.left) / sprite.width;
+        for y in sprite.start_y..sprite.end_y {
+            if depth[y as usize] < sprite.forward {
+                continue;
+            }
+            let tex_y = (y as f32 - sprite.top) / sprite.height;
+            let c = sprite.texture.sample_with(
+                tex_x,
+                tex_y,
+                sprite.texture.height as f32 / sprite.height,
+                self.sampling,
+            );
+            if c[3] > 0 {
+                let c = self.fog.apply(shade(c, sprite.light), sprite.forward);
+                let i = y as usize * 4;
+                let under = [pixels[i], pixels[i + 1], pixels[i + 2], pixels[i + 3]];
+                pixels[i..i + 4].copy_from_slice(&blend(under, c));
             }
         }
     }
 
-    fn sample_texture(&self, x: f32, y: f32, side: HitSide) -> [u8; 4] {
-        let grid_x = x * self.tex_width as f32;
-        let grid_y = y * self.tex_height as f32;
-        let start = grid_x as usize * 4 + grid_y as usize * self.tex_width * 4;
+    /// Project the sprites on storey `storey`, whose floor is `eye_z` below
+    /// the eye, into the view as camera facing billboards, sorted from
+    /// furthest to nearest so nearer sprites are drawn over further ones.
+    fn project_sprites<'a>(
+        &'a self,
+        player: &Player,
+        grid: &Grid,
+        eye_z: f32,
+        sprites: &[Sprite],
+        storey: usize,
+    ) -> Vec<SpriteProjection<'a>> {
+        let increment = self.increment();
+        let horizon = self.horizon(player) as f32;
+        let direction = [player.angle.cos(), player.angle.sin()];
+
+        // Transform each sprite into camera space: how far it is in front of
+        // the player and how far to the right.
+        let mut visible: Vec<(f32, f32, &Sprite)> = sprites
+            .iter()
+            .filter(|sprite| sprite.storey == storey)
+            .map(|sprite| {
+                let dx = sprite.x - player.x;
+                let dy = player.y - sprite.y;
+                let forward = dx * direction[0] + dy * direction[1];
+                let right = dx * direction[1] - dy * direction[0];
+                (forward, right, sprite)
+            })
+            .filter(|(forward, _, _)| *forward > 0.1)
+            .collect();
+        visible.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        visible
+            .into_iter()
+            .map(|(forward, right, sprite)| {
+                // Sprites are one unit across and one wall high, using the
+                // same projection as the walls and floor.
+                let centre = self.width as f32 / 2.0 + right / (forward * increment);
+                let width = 1.0 / (forward * increment);
+                let height = self.height as f32 / forward;
+                let floor = grid.floor_height_at(sprite.x.floor() as i32, sprite.y.floor() as i32);
+                let above_eye = (floor + 1.0 - eye_z) * height;
+                let left = centre - width / 2.0;
+                let top = horizon - above_eye;
+                SpriteProjection {
+                    texture: self.textures.get(sprite.texture),
+                    forward,
+                    light: grid.light_at(sprite.x.floor() as i32, sprite.y.floor() as i32),
+                    left,
+                    top,
+                    width,
+                    height,
+                    start_x: (left.ceil() as i32).max(0),
+                    end_x: ((left + width).ceil() as i32).min(self.width),
+                    start_y: (top.ceil() as i32).max(0),
+                    end_y: ((top + height).ceil() as i32).min(self.height),
+                }
+            })
+            .collect()
+    }
+
+    /// Draw the floor and ceiling of a stretch of a column, in the rows where
+    /// they are within the stretch and nothing nearer has been drawn. `ray` is
+    /// the column's direction scaled to be one unit long along the view
+    /// direction.
+    #[allow(clippy::too_many_arguments)]
+    fn draw_stretch(
+        &self,
+        pixels: &mut [u8],
+        depth: &mut [f32],
+        stretch: &Stretch,
+        column: &Column,
+        layer: &Layer,
+        horizon: i32,
+        ray: Vector2<f32>,
+    ) {
+        // Invert the wall projection `line_height = height / distance` to
+        // find which rows meet the floor or ceiling within the stretch, from
+        // how far below or above the eye they are. The rows are split the
+        // same way as wall slices so the two meet without gaps, but sampled
+        // through their centres.
+        let height = self.height as f32;
+        let horizon_row = horizon as f32 - 0.5;
+        let drop = (layer.eye_z - stretch.floor) * height;
+        let rise = (stretch.ceiling - layer.eye_z) * height;
+        let floor_rows = if drop > 0.0 {
+            let first = (horizon as f32 + drop / stretch.far).ceil();
+            let last = (horizon as f32 + drop / stretch.near).ceil();
+            first.max(0.0) as i32..last.min(height) as i32
+        } else {
+            0..0
+        };
+        let ceiling_rows = if rise > 0.0 {
+            let first = (horizon as f32 - rise / stretch.near).ceil();
+            let last = (horizon as f32 - rise / stretch.far).ceil();
+            first.max(0.0) as i32..last.min(height) as i32
+        } else {
+            0..0
+        };
 
-        let mut c: [u8; 4] = [0; 4];
-        c.copy_from_slice(&self.texture[start..start + 4]);
-        // darken the x direction tile sides
-        match side {
-            HitSide::X => {
-                c[0..3].iter_mut().for_each(|c| {
-                    *c = (*c as f32 * 0.6) as u8;
-                });
+        let rows = floor_rows
+            .map(|y| (y, drop / (y as f32 - horizon_row), true))
+            .chain(ceiling_rows.map(|y| (y, rise / (horizon_row - y as f32), false)));
+        for (y, distance, is_floor) in rows {
+            if depth[y as usize] != f32::INFINITY {
+                continue;
             }
-            _ => {}
+            let i = y as usize * 4;
+            let rows_from_horizon = (y as f32 - horizon_row).abs();
+            match self.sample_surface(
+                is_floor,
+                distance,
+                rows_from_horizon,
+                ray,
+                &column.legs,
+                layer,
+            ) {
+                Surface::Solid(c) => {
+                    depth[y as usize] = distance;
+                    pixels[i..i + 4].copy_from_slice(&c);
+                }
+                Surface::Hole => depth[y as usize] = distance,
+                Surface::Sky(c) => pixels[i..i + 4].copy_from_slice(&c),
+            }
+        }
+    }
+
+    /// Sample the floor or ceiling `distance` away, `rows_from_horizon` rows
+    /// from the horizon. `ray` is the column's direction scaled to be one
+    /// unit long along the view direction, so it only needs multiplying by
+    /// the distance. Rows further away than a mirror or portal the ray
+    /// bounced off or went through follow the later `legs` of the ray
+    /// instead.
+    fn sample_surface(
+        &self,
+        is_floor: bool,
+        distance: f32,
+        rows_from_horizon: f32,
+        ray: Vector2<f32>,
+        legs: &[Leg],
+        layer: &Layer,
+    ) -> Surface {
+        let along = distance * vec2_len(ray);
+        let leg = legs
+            .iter()
+            .rfind(|leg| leg.travelled <= along)
+            .unwrap_or(&legs[0]);
+        let [world_x, world_y] = leg.point(vec2_scale(leg.direction, along - leg.travelled));
+        let tile_x = world_x.floor() as i32;
+        let tile_y = world_y.floor() as i32;
+        let grid = layer.grid;
+        let hole = if is_floor {
+            layer.see_down && grid.floor_at(tile_x, tile_y) == OPENING
+        } else {
+            layer
+                .above
+                .is_some_and(|above| above.floor_at(tile_x, tile_y) == OPENING)
+        };
+        if hole {
+            return Surface::Hole;
+        }
+        let texture = if is_floor {
+            self.textures.floor(grid.floor_at(tile_x, tile_y))
+        } else {
+            self.textures.ceiling(grid.ceiling_at(tile_x, tile_y))
+        };
+        let light = grid.light_at(tile_x, tile_y);
+        match texture {
+            Some(texture) => {
+                // Pixels are as wide across the ray as on a wall this far away
+                let texels_per_pixel = texture.width as f32 * distance / self.height as f32;
+                let c = texture.sample_with(
+                    world_x.fract(),
+                    world_y.fract(),
+                    texels_per_pixel,
+                    self.sampling,
+                );
+                Surface::Solid(self.fog.apply(shade(c, light), distance))
+            }
+            None if is_floor => {
+                Surface::Solid(self.fog.apply(shade(FLOOR_COLOUR, light), distance))
+            }
+            // the sky is infinitely far away, so fog would hide it entirely
+            None => Surface::Sky(self.sample_sky(rows_from_horizon, leg.direction)),
         }
-        return c;
+    }
+
+    /// Sample the sky `rows_from_horizon` rows above the horizon in the
+    /// direction of `ray`.
+    fn sample_sky(&self, rows_from_horizon: f32, ray: Vector2<f32>) -> [u8; 4] {
+        let texture = match self.sky {
+            Some(index) => self.textures.get(index),
+            None => return SKY_COLOUR,
+        };
+        // Turning right scrolls the sky left, and a full turn is the width of
+        // the texture.
+        let u = (-ray[1].atan2(ray[0]) / TAU).rem_euclid(1.0);
+        let elevation = (rows_from_horizon / self.height as f32).atan();
+        let v = 1.0 - elevation / FRAC_PI_2;
+        // Each row is a 1 / height radian step up the sky near the horizon
+        let texels_per_pixel = texture.height as f32 / (FRAC_PI_2 * self.height as f32);
+        texture.sample_with(u, v.clamp(0.0, 1.0), texels_per_pixel, self.sampling)
+    }
+
+    /// Sample a wall texture, or the untextured floor colour without one,
+    /// shading it by which way the face points and the `light` falling on it,
+    /// then fogging it by its `distance` from the player.
+    fn sample_texture(
+        &self,
+        texture: Option<&Texture>,
+        x: f32,
+        y: f32,
+        normal: Vector2<f32>,
+        light: f32,
+        distance: f32,
+    ) -> [u8; 4] {
+        // The texture is one wall height tall, which is height / distance
+        // pixels
+        let c = texture.map_or(FLOOR_COLOUR, |texture| {
+            let texels_per_pixel = texture.height as f32 * distance / self.height as f32;
+            texture.sample_with(x, y, texels_per_pixel, self.sampling)
+        });
+        // darken faces pointing along y, blending smoothly for diagonal walls
+        let light = light * (0.6 + 0.4 * normal[0] * normal[0]);
+        self.fog.apply(shade(c, light), distance)
+    }
+}
+
+impl Default for Viewport {
+    fn default() -> Self {
+        Self::new()
     }
 }
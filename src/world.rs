@@ -1,51 +1,329 @@
 use crate::clear;
-use crate::grid::Grid;
-use crate::player::Player;
+use crate::collision::floor_and_ceiling;
+use crate::drawing::Frame;
+use crate::framebuffer::Framebuffer;
+use crate::grid::{Grid, TileKind, OPENING};
+use crate::level::{Level, LevelError};
+use crate::player::{Player, EYE_HEIGHT};
+use crate::sprite::Sprite;
+use crate::texture::Sampling;
 use crate::viewport::Viewport;
+use std::path::Path;
+use winit::event::VirtualKeyCode;
 use winit_input_helper::WinitInputHelper;
 
+/// Level loaded by [`World::init`].
+pub const DEFAULT_LEVEL: &str = "assets/levels/default.ron";
+
 pub struct World {
-    grid: Grid,
+    /// The storeys of the level from the bottom up.
+    storeys: Vec<Grid>,
+    /// Which of the storeys the player is on.
+    storey: usize,
     player: Player,
     viewport: Viewport,
+    sprites: Vec<Sprite>,
+    width: i32,
+    height: i32,
 }
 
 impl World {
-    pub fn new() -> World {
-        World {
-            grid: Grid {
-                tiles: Vec::new(),
-                width: 0,
-                height: 0,
-                tile_size: 0,
-            },
+    /// Create an empty world that renders into a `width` by `height` frame.
+    pub fn new(width: i32, height: i32) -> World {
+        let mut world = World {
+            storeys: vec![Grid::new(0, 0)],
+            storey: 0,
             player: Player {
                 x: 0.0,
                 y: 0.0,
                 angle: 0.0,
+                pitch: 0.0,
+                radius: 0.25,
+                elevation: 0.0,
+                vertical_speed: 0.0,
+                eye_height: EYE_HEIGHT,
+                ceiling: 1.0,
             },
             viewport: Viewport::new(),
+            sprites: Vec::new(),
+            width,
+            height,
+        };
+        world.resize(width, height);
+        world
+    }
+
+    /// Load the default level.
+    pub fn init(&mut self) -> Result<(), LevelError> {
+        self.load_level(DEFAULT_LEVEL)
+    }
+
+    /// Replace the current level with one loaded from `path`, which may be a
+    /// level file or a PNG map, and move the player to its spawn point.
+    pub fn load_level<P: AsRef<Path>>(&mut self, path: P) -> Result<(), LevelError> {
+        self.set_level(Level::load(path)?);
+        Ok(())
+    }
+
+    pub fn set_level(&mut self, level: Level) {
+        self.storeys = level.storeys;
+        self.storey = level.spawn.storey;
+        self.sprites = level.sprites;
+        self.viewport.set_textures(level.textures);
+        self.viewport.set_view_distance(level.view_distance);
+        self.viewport.set_fog(level.fog);
+        self.viewport.set_sky(level.sky);
+        self.set_player_pose(level.spawn.x, level.spawn.y, level.spawn.angle);
+        self.resize(self.width, self.height);
+    }
+
+    /// Change the size of the frame the world renders into, laying out the
+    /// minimap on the left and the 3D viewport to its right.
+    pub fn resize(&mut self, width: i32, height: i32) {
+        self.width = width;
+        self.height = height;
+        let minimap_size = height.min(width / 2);
+        for grid in &mut self.storeys {
+            if grid.width > 0 && grid.height > 0 {
+                grid.fit(minimap_size);
+            }
+        }
+        self.viewport.layout(width, height, minimap_size);
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    /// The storey the player is on.
+    pub fn grid(&self) -> &Grid {
+        &self.storeys[self.storey]
+    }
+
+    pub fn grid_mut(&mut self) -> &mut Grid {
+        &mut self.storeys[self.storey]
+    }
+
+    pub fn storeys(&self) -> &[Grid] {
+        &self.storeys
+    }
+
+    /// Which storey the player is on, counting up from 0.
+    pub fn storey(&self) -> usize {
+        self.storey
+    }
+
+    /// Move the player to another storey, keeping their position. Storeys the
+    /// level doesn't have are ignored.
+    pub fn set_storey(&mut self, storey: usize) {
+        if storey < self.storeys.len() {
+            self.storey = storey;
         }
     }
 
-    pub fn init(&mut self) {
-        self.grid.init();
-        self.player.x = self.grid.width as f32 / 2.0;
-        self.player.y = self.grid.height as f32 / 2.0;
-        self.viewport.init();
+    pub fn player(&self) -> &Player {
+        &self.player
     }
+
+    pub fn sprites(&self) -> &[Sprite] {
+        &self.sprites
+    }
+
+    pub fn sprites_mut(&mut self) -> &mut Vec<Sprite> {
+        &mut self.sprites
+    }
+
+    pub fn sampling(&self) -> Sampling {
+        self.viewport.sampling()
+    }
+
+    /// Choose how textures are filtered.
+    pub fn set_sampling(&mut self, sampling: Sampling) {
+        self.viewport.set_sampling(sampling);
+    }
+
+    /// Place the player on the floor at a position in grid units, facing
+    /// `angle` radians and looking straight ahead.
+    pub fn set_player_pose(&mut self, x: f32, y: f32, angle: f32) {
+        let (floor, ceiling) = floor_and_ceiling(self.grid(), [x, y], self.player.radius);
+        self.player.x = x;
+        self.player.y = y;
+        self.player.angle = angle;
+        self.player.pitch = 0.0;
+        self.player.elevation = floor;
+        self.player.vertical_speed = 0.0;
+        self.player.ceiling = ceiling;
+    }
+
+    /// Turn the player from mouse movement, already scaled to radians. A
+    /// positive `yaw` turns to the right and a positive `pitch` looks down,
+    /// matching the way the mouse moves.
+    pub fn look(&mut self, yaw: f32, pitch: f32) {
+        self.player.turn(-yaw);
+        self.player.tilt(-pitch);
+    }
+
     /// Update everything in the world
     pub fn update(&mut self, input: &WinitInputHelper, delta: f32) {
-        self.player.update(input, &self.grid, delta);
+        let tile = self.player_tile();
+        self.player.update(input, &self.storeys[self.storey], delta);
+        if self.player_tile() != tile {
+            self.take_stairs();
+        }
+        self.fall_through_hole();
+
+        if input.key_pressed(VirtualKeyCode::E) {
+            let (x, y) = self.player.facing_tile();
+            // don't shut a door on the player
+            if self.player_tile() != (x, y) {
+                self.grid_mut().toggle_door(x, y);
+            }
+        }
+        // F and M switch bilinear filtering and mipmaps on and off, to compare
+        // how they look and how fast they draw
+        let mut sampling = self.sampling();
+        if input.key_pressed(VirtualKeyCode::F) {
+            sampling.bilinear = !sampling.bilinear;
+        }
+        if input.key_pressed(VirtualKeyCode::M) {
+            sampling.mipmaps = !sampling.mipmaps;
+        }
+        self.set_sampling(sampling);
+        for grid in &mut self.storeys {
+            grid.update(delta);
+        }
+        self.viewport.textures_mut().update(delta);
+    }
+
+    /// The tile the player is standing in.
+    fn player_tile(&self) -> (i32, i32) {
+        (self.player.x as i32, self.player.y as i32)
+    }
+
+    /// Move the player up or down a storey if they've just stepped onto
+    /// stairs. They arrive on the same tile of the next storey, so stairs
+    /// going the other way there are only taken once the player steps off and
+    /// back on again. Storeys are a wall height apart, so the player keeps
+    /// their height relative to both and lands on whatever floor is there,
+    /// straight away if it's above their feet.
+    fn take_stairs(&mut self) {
+        let (x, y) = self.player_tile();
+        let kind = self.grid().kind_at(x, y);
+        if kind == TileKind::StairsDown && self.storey > 0 {
+            self.set_storey(self.storey - 1);
+            self.player.elevation += 1.0;
+        } else if kind == TileKind::StairsUp && self.storey + 1 < self.storeys.len() {
+            self.set_storey(self.storey + 1);
+            self.player.elevation -= 1.0;
+            let position = [self.player.x, self.player.y];
+            let (floor, _) = floor_and_ceiling(self.grid(), position, self.player.radius);
+            self.player.elevation = self.player.elevation.max(floor);
+        }
     }
 
-    /// Draw the `World` state to the frame buffer.
+    /// Drop the player to the storey below once they're down at the level of
+    /// a hole in the floor, so they can jump across one, or stand on its edge
+    /// while a higher floor beside it holds them up.
+    fn fall_through_hole(&mut self) {
+        let (x, y) = self.player_tile();
+        let grid = self.grid();
+        if self.storey > 0
+            && grid.floor_at(x, y) == OPENING
+            && self.player.elevation <= grid.floor_height_at(x, y)
+        {
+            self.set_storey(self.storey - 1);
+            self.player.elevation += 1.0;
+        }
+    }
+
+    /// Draw the `World` state to the frame buffer, which must match the size
+    /// given to [`World::resize`].
     ///
     /// Assumes the default texture format: `wgpu::TextureFormat::Rgba8UnormSrgb`
     pub fn draw(&self, frame: &mut [u8]) {
-        clear(frame);
-        self.grid.draw(frame);
-        self.player.draw(frame, &self.grid);
-        self.viewport.draw(frame, &self.player, &self.grid);
+        let mut frame = Frame::new(frame, self.width, self.height);
+        // Cast once for both the minimap and the 3D view
+        let columns = self.viewport.cast(&self.player, self.grid());
+        clear(&mut frame);
+        self.grid().draw(&mut frame);
+        self.player.draw(
+            &mut frame,
+            self.grid(),
+            &columns,
+            self.viewport.view_distance(),
+        );
+        self.viewport.draw(
+            &mut frame,
+            &self.player,
+            &self.storeys,
+            self.storey,
+            &self.sprites,
+            &columns,
+        );
+    }
+
+    /// Draw the `World` into an offscreen [`Framebuffer`], resizing it to
+    /// match the world if needed.
+    pub fn render(&self, framebuffer: &mut Framebuffer) {
+        if framebuffer.width() != self.width || framebuffer.height() != self.height {
+            framebuffer.resize(self.width, self.height);
+        }
+        self.draw(framebuffer.frame_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_tiny_frames() {
+        for (width, height) in [(40, 40), (12, 3), (1, 1)] {
+            let mut world = World::new(width, height);
+            world.init().unwrap();
+            let mut framebuffer = Framebuffer::new(width, height);
+            world.render(&mut framebuffer);
+        }
+    }
+
+    #[test]
+    fn climbs_stairs_onto_the_floor_above() {
+        let mut world = World::new(40, 40);
+        let mut lower = Grid::new(3, 1);
+        lower.kinds[1] = TileKind::StairsUp;
+        lower.tiles[1] = 1;
+        lower.floor_heights[1] = 0.3;
+        world.storeys = vec![lower, Grid::new(3, 1)];
+        world.set_player_pose(1.5, 0.5, 0.0);
+        world.player.elevation = 0.3;
+
+        world.take_stairs();
+        assert_eq!(world.storey(), 1);
+        assert_eq!(world.player().elevation, 0.0);
+    }
+
+    #[test]
+    fn falls_through_holes_only_from_the_floor() {
+        let mut world = World::new(40, 40);
+        let mut upper = Grid::new(3, 1);
+        upper.floor[1] = OPENING;
+        world.storeys = vec![Grid::new(3, 1), upper];
+        world.storey = 1;
+        world.set_player_pose(1.5, 0.5, 0.0);
+
+        // jumping over the hole
+        world.player.elevation = 0.3;
+        world.fall_through_hole();
+        assert_eq!(world.storey(), 1);
+
+        // coming down into it
+        world.player.elevation = 0.0;
+        world.fall_through_hole();
+        assert_eq!(world.storey(), 0);
+        assert_eq!(world.player().elevation, 1.0);
     }
 }
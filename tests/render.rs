@@ -0,0 +1,46 @@
+//! Renders the default level headlessly and compares it with a checked-in
+//! image, to catch changes to what gets drawn.
+//!
+//! After a change that is meant to alter the picture, regenerate the image
+//! with `UPDATE_GOLDEN=1 cargo test --test render` and check it by eye.
+
+use raycast::{Framebuffer, World};
+
+const GOLDEN: &str = "tests/golden/default.png";
+const WIDTH: i32 = 640;
+const HEIGHT: i32 = 225;
+
+/// How far apart a channel may be before a pixel counts as different, to
+/// allow for floating point differences between platforms.
+const TOLERANCE: u8 = 2;
+
+#[test]
+fn renders_the_default_level() {
+    let mut world = World::new(WIDTH, HEIGHT);
+    world.init().unwrap();
+    let mut framebuffer = Framebuffer::new(WIDTH, HEIGHT);
+    world.render(&mut framebuffer);
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        framebuffer.save_png(GOLDEN).unwrap();
+        return;
+    }
+    let golden = image::open(GOLDEN).unwrap().into_rgba8();
+    assert_eq!(golden.dimensions(), (WIDTH as u32, HEIGHT as u32));
+    let different = golden
+        .as_raw()
+        .chunks(4)
+        .zip(framebuffer.frame().chunks(4))
+        .filter(|(expected, actual)| {
+            expected
+                .iter()
+                .zip(actual.iter())
+                .any(|(e, a)| e.abs_diff(*a) > TOLERANCE)
+        })
+        .count();
+    assert_eq!(
+        different, 0,
+        "{} pixels differ from {}; run with UPDATE_GOLDEN=1 if that's intended",
+        different, GOLDEN
+    );
+}